@@ -0,0 +1,87 @@
+//! Compares `VM::run`, which decodes each instruction as it reaches it, with
+//! `VM::run_decoded`, which decodes the whole program first. The decoded
+//! timings include decoding.
+//!
+//! Run with `cargo bench --bench dispatch`.
+
+#[macro_use]
+extern crate criterion;
+
+use criterion::{black_box, BenchmarkId, Criterion};
+
+use bedrock::assembler::Assembler;
+use bedrock::vm::VM;
+
+/// Integer arithmetic in a tight loop
+const ARITHMETIC: &str = "
+load r0 100000
+load r1 0
+load r2 0
+load r3 1000003
+loop: addi r1 r1 7
+muli r1 r1 3
+mod r1 r3 r1
+subi r0 r0 1
+cmp r0 r2
+jgt @loop
+hlt
+";
+
+/// Sums 1..n over a loop that calls a subroutine every iteration
+const CALLS: &str = "
+load r0 50000
+load r1 0
+load r2 0
+loop: call @step
+subi r0 r0 1
+cmp r0 r2
+jgt @loop
+hlt
+step: add r1 r0 r1
+ret
+";
+
+/// Nested loops with float arithmetic and register jumps
+const NESTED: &str = "
+load r0 300
+load r5 0
+loadf r6 0.5
+loadf r7 0
+outer: load r1 300
+inner: mulf r6 r6 r8
+addf r7 r8 r7
+subi r1 r1 1
+cmp r1 r5
+jgt @inner
+subi r0 r0 1
+cmp r0 r5
+jgt @outer
+hlt
+";
+
+fn dispatch_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dispatch");
+    for (name, source) in &[("arithmetic", ARITHMETIC), ("calls", CALLS), ("nested", NESTED)] {
+	let program = Assembler::new().assemble(source).unwrap();
+	group.bench_with_input(BenchmarkId::new("run", name), &program, |b, program| {
+	    b.iter(|| {
+		let mut vm = VM::new();
+		vm.program = program.clone();
+		vm.run().unwrap();
+		black_box(vm.registers[1])
+	    })
+	});
+	group.bench_with_input(BenchmarkId::new("run_decoded", name), &program, |b, program| {
+	    b.iter(|| {
+		let mut vm = VM::new();
+		vm.program = program.clone();
+		vm.run_decoded().unwrap();
+		black_box(vm.registers[1])
+	    })
+	});
+    }
+    group.finish();
+}
+
+criterion_group!(benches, dispatch_benchmark);
+criterion_main!(benches);
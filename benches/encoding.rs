@@ -0,0 +1,132 @@
+//! Compares the LEB128 operand encoding against the fixed 8 byte encoding
+//! bytecode used before it, both in size and in how fast it decodes.
+//!
+//! Run with `cargo bench`. The sizes of both encodings are printed first.
+
+#[macro_use]
+extern crate criterion;
+
+use criterion::{black_box, Criterion};
+
+use bedrock::assembler::Assembler;
+use bedrock::disassembler::{self, Operand};
+use bedrock::encoding::{read_float, read_sleb, read_uleb};
+use bedrock::instruction::{Opcode, OperandKind};
+use bedrock::vm::VM;
+
+/// A loop that counts down from 10000, in the style of typical programs:
+/// mostly small constants, a few large ones and label targets
+const PROGRAM: &str = "
+load r0 10000
+load r1 0
+load r2 1
+load r8 0
+loadptr r9 42
+loop: add r1 r2 r1
+addi r3 r1 7
+muli r4 r3 -2
+load r5 -3
+load r6 4294967296
+loadf r7 1.5
+subi r0 r0 1
+cmp r0 r8
+jgt @loop
+hlt
+";
+
+/// Re-encodes `bytes` with every immediate as 8 bytes, signed ones in sign
+/// magnitude, which is how bytecode was laid out before LEB128
+fn to_fixed(bytes: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    for instruction in disassembler::disassemble(bytes).unwrap() {
+	out.push(instruction.byte);
+	for operand in instruction.operands {
+	    match operand {
+		Operand::Register(r) => out.push(r),
+		Operand::Int(v) => {
+		    let magnitude = v.unsigned_abs() | if v < 0 { 1 << 63 } else { 0 };
+		    out.extend_from_slice(&magnitude.to_be_bytes());
+		},
+		Operand::UInt(v) => out.extend_from_slice(&v.to_be_bytes()),
+		Operand::Float(v) => out.extend_from_slice(&v.to_bits().to_be_bytes()),
+	    }
+	}
+    }
+    out
+}
+
+fn read_fixed(bytes: &[u8], pc: usize) -> u64 {
+    bytes[pc..pc + 8].iter().fold(0, |acc, b| (acc << 8) | u64::from(*b))
+}
+
+/// Walks every operand the way the VM's old decoder did, summing them so the
+/// work is not optimised away
+fn decode_fixed(bytes: &[u8]) -> u64 {
+    let mut pc = 0;
+    let mut sum = 0u64;
+    while pc < bytes.len() {
+	let opcode = Opcode::from(bytes[pc]);
+	pc += 1;
+	for kind in opcode.operands() {
+	    match kind {
+		OperandKind::Register => {
+		    sum = sum.wrapping_add(u64::from(bytes[pc]));
+		    pc += 1;
+		},
+		OperandKind::Int => {
+		    let v = read_fixed(bytes, pc);
+		    let magnitude = (v & !(1 << 63)) as i64;
+		    let v = if v >> 63 > 0 { -magnitude } else { magnitude };
+		    sum = sum.wrapping_add(v as u64);
+		    pc += 8;
+		},
+		OperandKind::UInt | OperandKind::Float => {
+		    sum = sum.wrapping_add(read_fixed(bytes, pc));
+		    pc += 8;
+		},
+	    }
+	}
+    }
+    sum
+}
+
+fn decode_leb(bytes: &[u8]) -> u64 {
+    let mut pc = 0;
+    let mut sum = 0u64;
+    while pc < bytes.len() {
+	let opcode = Opcode::from(bytes[pc]);
+	pc += 1;
+	for kind in opcode.operands() {
+	    let (v, len) = match kind {
+		OperandKind::Register => (u64::from(bytes[pc]), 1),
+		OperandKind::Int => read_sleb(bytes, pc).map(|(v, len)| (v as u64, len)).unwrap(),
+		OperandKind::UInt => read_uleb(bytes, pc).unwrap(),
+		OperandKind::Float => read_float(bytes, pc).map(|(v, len)| (v.to_bits(), len)).unwrap(),
+	    };
+	    sum = sum.wrapping_add(v);
+	    pc += len;
+	}
+    }
+    sum
+}
+
+fn encoding_benchmark(c: &mut Criterion) {
+    let leb = Assembler::new().assemble(PROGRAM).unwrap();
+    let fixed = to_fixed(&leb);
+    assert_eq!(decode_leb(&leb), decode_fixed(&fixed));
+    println!("program size: {} bytes with LEB128, {} bytes fixed width", leb.len(), fixed.len());
+
+    c.bench_function("decode leb128", |b| b.iter(|| decode_leb(black_box(&leb))));
+    c.bench_function("decode fixed width", |b| b.iter(|| decode_fixed(black_box(&fixed))));
+    c.bench_function("run countdown loop", |b| {
+	b.iter(|| {
+	    let mut vm = VM::new();
+	    vm.program = leb.clone();
+	    vm.run().unwrap();
+	    black_box(vm.registers[1])
+	})
+    });
+}
+
+criterion_group!(benches, encoding_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,73 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::assembler::symbols::SymbolType;
+use crate::bytecode::MAX_SYMBOL_NAME_LEN;
+use crate::instruction::OperandKind;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AssemblerError {
+    ParseError { error: String },
+    NonOpcodeInOpcodeField,
+    OpcodeInOperandField,
+    /// The instruction was given more or fewer operands than its opcode takes
+    WrongOperandCount { mnemonic: String, expected: usize, found: usize },
+    /// A register where an immediate belongs, or the other way round. Operands
+    /// are numbered from 1.
+    WrongOperandKind { mnemonic: String, position: usize, expected: OperandKind },
+    FloatInIntegerField,
+    IntegerOutOfRange { literal: String },
+    NonNumberInFloatField,
+    UnknownLabel { name: String },
+    /// A `@name` operand naming the wrong kind of symbol for its instruction
+    WrongSymbolType { name: String, symbol_type: SymbolType, mnemonic: String },
+    LabelOutOfRange { name: String },
+    SymbolAlreadyDeclared { name: String },
+    SymbolNameTooLong { length: usize },
+    MisplacedLabel { name: String },
+    UnknownDirective { name: String },
+    InvalidDirectiveOperand { directive: String },
+    DataOutsideDataSection { directive: String },
+    InstructionOutsideCodeSection,
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	match self {
+	    AssemblerError::ParseError { error } => write!(f, "Unable to parse input: {}", error),
+	    AssemblerError::NonOpcodeInOpcodeField => write!(f, "Non-opcode found in opcode field"),
+	    AssemblerError::OpcodeInOperandField => write!(f, "Opcode found in operand field"),
+	    AssemblerError::WrongOperandCount { mnemonic, expected, found } => {
+		write!(f, "{} takes {} operands but was given {}", mnemonic, expected, found)
+	    },
+	    AssemblerError::WrongOperandKind { mnemonic, position, expected: OperandKind::Register } => {
+		write!(f, "Operand {} of {} must be a register", position, mnemonic)
+	    },
+	    AssemblerError::WrongOperandKind { mnemonic, position, .. } => {
+		write!(f, "Operand {} of {} must be an immediate", position, mnemonic)
+	    },
+	    AssemblerError::FloatInIntegerField => write!(f, "Float found where an integer operand is expected"),
+	    AssemblerError::IntegerOutOfRange { literal } => write!(f, "Integer {} is out of range", literal),
+	    AssemblerError::NonNumberInFloatField => write!(f, "Float operand expected"),
+	    AssemblerError::UnknownLabel { name } => write!(f, "Label {} was used but never declared", name),
+	    AssemblerError::WrongSymbolType { name, symbol_type, mnemonic } => {
+		let kind = match symbol_type {
+		    SymbolType::Label => "a code label",
+		    SymbolType::Data => "a data label",
+		    SymbolType::Extern => "an extern",
+		};
+		write!(f, "{} is {}, which {} cannot take", name, kind, mnemonic)
+	    },
+	    AssemblerError::LabelOutOfRange { name } => write!(f, "Label {} is too far away to reference", name),
+	    AssemblerError::SymbolAlreadyDeclared { name } => write!(f, "Label {} was declared more than once", name),
+	    AssemblerError::SymbolNameTooLong { length } => write!(f, "Symbol name of {} bytes is longer than the limit of {}", length, MAX_SYMBOL_NAME_LEN),
+	    AssemblerError::MisplacedLabel { name } => write!(f, "Label {} cannot be attached to a section directive", name),
+	    AssemblerError::UnknownDirective { name } => write!(f, "Unknown directive .{}", name),
+	    AssemblerError::InvalidDirectiveOperand { directive } => write!(f, "Invalid operand for .{}", directive),
+	    AssemblerError::DataOutsideDataSection { directive } => write!(f, ".{} is only allowed in the .data section", directive),
+	    AssemblerError::InstructionOutsideCodeSection => write!(f, "Instructions are only allowed in the .code section"),
+	}
+    }
+}
+
+impl Error for AssemblerError {}
@@ -1,7 +1,8 @@
-use nom::alpha1;
+use nom::{alpha1, alphanumeric, multispace};
 use nom::types::CompleteStr;
 
 use crate::assembler::Token;
+use crate::assembler::label_parsers::label_declaration;
 
 use super::instruction_parsers::AssemblerInstruction;
 use super::operand_parsers::*;
@@ -19,16 +20,17 @@ named!(directive_declaration<CompleteStr, Token>,
 named!(directive_combined<CompleteStr, AssemblerInstruction>,
     ws!(
         do_parse!(
-            tag!(".") >>
+	    l: opt!(label_declaration) >>
             name: directive_declaration >>
             o1: opt!(operand) >>
             o2: opt!(operand) >>
             o3: opt!(operand) >>
+	    opt!(multispace) >>
             (
                 AssemblerInstruction{
                     opcode: None,
                     directive: Some(name),
-                    label: None,
+		    label: l,
                     operand1: o1,
                     operand2: o2,
                     operand3: o3,
@@ -38,14 +40,113 @@ named!(directive_combined<CompleteStr, AssemblerInstruction>,
     )
 );
 
-/// Will try to parse out any of the Directive forms
+// A label followed directly by a string, such as `name: "world"`, is shorthand for `.asciiz`
+named!(string_shorthand<CompleteStr, AssemblerInstruction>,
+    ws!(
+	do_parse!(
+	    l: label_declaration >>
+	    s: irstring >>
+	    opt!(multispace) >>
+	    (
+		AssemblerInstruction{
+		    opcode: None,
+		    directive: Some(Token::Directive{name: "asciiz".to_string()}),
+		    label: Some(l),
+		    operand1: Some(s),
+		    operand2: None,
+		    operand3: None,
+		}
+	    )
+	)
+    )
+);
+
+// `.extern name` declares a host function, the same as `name: .extern`
+named!(extern_declaration<CompleteStr, AssemblerInstruction>,
+    do_parse!(
+	opt!(multispace) >>
+	tag!(".extern") >>
+	multispace >>
+	name: alphanumeric >>
+	opt!(multispace) >>
+	(
+	    AssemblerInstruction{
+		opcode: None,
+		directive: Some(Token::Directive{name: "extern".to_string()}),
+		label: Some(Token::LabelDeclaration{name: name.to_string()}),
+		operand1: None,
+		operand2: None,
+		operand3: None,
+	    }
+	)
+    )
+);
+
+// Will try to parse out any of the Directive forms
 named!(pub directive<CompleteStr, AssemblerInstruction>,
     do_parse!(
         ins: alt!(
-            directive_combined
+	    extern_declaration |
+	    directive_combined |
+	    string_shorthand
         ) >>
         (
             ins
         )
     )
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parser_directive() {
+	let result = directive_declaration(CompleteStr(".data"));
+	assert!(result.is_ok());
+	let (_, directive) = result.unwrap();
+	assert_eq!(directive, Token::Directive { name: "data".to_string() })
+    }
+
+    #[test]
+    fn test_string_directive() {
+	let result = directive_combined(CompleteStr("test: .asciiz 'Hello'"));
+	assert!(result.is_ok());
+	let (_, directive) = result.unwrap();
+
+	// Yes, this is the what the result should be
+	let correct_instruction =
+	    AssemblerInstruction {
+		opcode: None,
+		label: Some(
+		    Token::LabelDeclaration {
+			name: "test".to_string()
+		    }),
+		directive: Some(
+		    Token::Directive {
+			name: "asciiz".to_string()
+		    }),
+		operand1: Some(Token::IrString { name: "Hello".to_string() }),
+		operand2: None,
+		operand3: None };
+
+	assert_eq!(directive, correct_instruction);
+    }
+
+    #[test]
+    fn test_string_shorthand() {
+	let (rest, shorthand) = directive(CompleteStr("wname: \"world\"\n")).unwrap();
+	let (_, longhand) = directive(CompleteStr("wname: .asciiz 'world'")).unwrap();
+	assert_eq!(rest, CompleteStr(""));
+	assert_eq!(shorthand, longhand);
+    }
+
+    #[test]
+    fn test_extern_directive() {
+	let (rest, short) = directive(CompleteStr(".extern println\n")).unwrap();
+	let (_, long) = directive(CompleteStr("println: .extern")).unwrap();
+	assert_eq!(rest, CompleteStr(""));
+	assert_eq!(short, long);
+	assert_eq!(short.label_name(), Some("println"));
+    }
+}
@@ -1,92 +1,202 @@
 use crate::assembler::Token;
+use crate::assembler::assembler_errors::AssemblerError;
+use crate::assembler::label_parsers::label_declaration;
 use crate::assembler::opcode_parsers::*;
 use crate::assembler::operand_parsers::operand;
+use crate::assembler::symbols::{SymbolTable, SymbolType};
+use crate::encoding::{sleb_len, uleb_len, write_float, write_sleb, write_uleb, write_uleb_padded, zigzag, LABEL_LEN};
+use crate::instruction::{Opcode, OperandKind};
 use nom::types::CompleteStr;
 use nom::multispace;
 
 #[derive(Debug, PartialEq)]
 pub struct AssemblerInstruction {
-    opcode: Token,
-    operand1: Option<Token>,
-    operand2: Option<Token>,
-    operand3: Option<Token>,
+    pub opcode: Option<Token>,
+    pub directive: Option<Token>,
+    pub label: Option<Token>,
+    pub operand1: Option<Token>,
+    pub operand2: Option<Token>,
+    pub operand3: Option<Token>,
 }
 
 impl AssemblerInstruction {
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut results = vec![];
-        match self.opcode {
-            Token::Op { code } => match code {
-                _ => {
-                    results.push(code as u8);
-                }
-            },
-            _ => {
-                println!("Non-opcode found in opcode field");
-                std::process::exit(1);
-            }
-        };
+    pub fn to_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, AssemblerError> {
+	let code = match self.resolved_opcode() {
+	    Some(code) => code,
+	    None => return Err(AssemblerError::NonOpcodeInOpcodeField),
+	};
+	let mut results = vec![code as u8];
+	for (token, kind) in self.checked_operands(code)? {
+	    AssemblerInstruction::extract_operand(token, code, kind, &mut results, symbols)?;
+	}
+	Ok(results)
+    }
+
+    /// The opcode to emit, which for jumps and calls depends on whether the
+    /// target is a register or an immediate
+    fn resolved_opcode(&self) -> Option<Opcode> {
+	match self.opcode {
+	    Some(Token::Op { code }) => match (code.immediate_form(), &self.operand1) {
+		(Some(immediate), Some(t)) if !AssemblerInstruction::is_register(t) => Some(immediate),
+		_ => Some(code),
+	    },
+	    _ => None,
+	}
+    }
 
-	for operand in &[&self.operand1, &self.operand2, &self.operand3] {
-	    if let Some(token) = operand {
-		AssemblerInstruction::extract_operand(token, &mut results)
+    /// Each operand paired with the kind of slot it fills, after checking
+    /// that `code` takes that many operands and that registers are given
+    /// where it expects them and nowhere else
+    fn checked_operands(&self, code: Opcode) -> Result<Vec<(&Token, OperandKind)>, AssemblerError> {
+	let kinds = code.operands();
+	let operands: Vec<&Token> = self.operands().collect();
+	if operands.len() != kinds.len() {
+	    return Err(AssemblerError::WrongOperandCount {
+		mnemonic: code.mnemonic().to_string(),
+		expected: kinds.len(),
+		found: operands.len(),
+	    });
+	}
+	for (position, (token, kind)) in operands.iter().zip(kinds).enumerate() {
+	    let misplaced = match kind {
+		OperandKind::Register => !AssemblerInstruction::is_register(token),
+		// `extract_float` says what a float slot needs
+		OperandKind::Float => false,
+		_ => AssemblerInstruction::is_register(token),
+	    };
+	    if misplaced {
+		return Err(AssemblerError::WrongOperandKind {
+		    mnemonic: code.mnemonic().to_string(),
+		    position: position + 1,
+		    expected: *kind,
+		});
 	    }
 	}
-        results
+	Ok(operands.into_iter().zip(kinds.iter().cloned()).collect())
+    }
+
+    /// Whether `@name` operands of `code` may name a symbol of type `symbol_type`
+    fn accepts_symbol(code: Opcode, symbol_type: SymbolType) -> bool {
+	match code {
+	    Opcode::CallNative => symbol_type == SymbolType::Extern,
+	    Opcode::Loadptr | Opcode::WritePtr => symbol_type == SymbolType::Data,
+	    code if code.has_immediate_target() => symbol_type == SymbolType::Label,
+	    _ => symbol_type != SymbolType::Extern,
+	}
+    }
+
+    fn is_register(t: &Token) -> bool {
+	matches!(t, Token::Register { .. })
+    }
+
+    /// Number of bytes `to_bytes` will produce, known before labels are resolved
+    /// because label references are always padded to `LABEL_LEN` bytes
+    pub fn byte_len(&self) -> u64 {
+	let code = match self.resolved_opcode() {
+	    Some(code) => code,
+	    None => return 0,
+	};
+	let operands = match self.checked_operands(code) {
+	    Ok(operands) => operands,
+	    // `to_bytes` reports the error
+	    Err(_) => return 0,
+	};
+	let mut len = 1;
+	for (token, kind) in operands {
+	    len += match (token, kind) {
+		(_, OperandKind::Float) => 8,
+		(Token::Register { .. }, _) => 1,
+		(Token::LabelUsage { .. }, _) => LABEL_LEN,
+		(Token::Pos { value }, OperandKind::Int) | (Token::Bits { value }, OperandKind::Int) => sleb_len(*value as i64),
+		(Token::Neg { value }, OperandKind::Int) => sleb_len(*value),
+		(Token::Pos { value }, _) | (Token::Bits { value }, _) => uleb_len(*value),
+		// Anything else is rejected by `to_bytes`
+		_ => 0,
+	    } as u64;
+	}
+	len
+    }
+
+    /// The name of the label declared on this line, if any
+    pub fn label_name(&self) -> Option<&str> {
+	match &self.label {
+	    Some(Token::LabelDeclaration { name }) => Some(name),
+	    _ => None,
+	}
+    }
+
+    /// The name of the directive on this line, such as `data` for `.data`
+    pub fn directive_name(&self) -> Option<&str> {
+	match &self.directive {
+	    Some(Token::Directive { name }) => Some(name),
+	    _ => None,
+	}
+    }
+
+    pub fn operands(&self) -> impl Iterator<Item = &Token> {
+	vec![&self.operand1, &self.operand2, &self.operand3].into_iter().flatten()
+    }
+
+    /// Encodes an operand the VM reads as an `f64`; integer literals are converted
+    fn extract_float(t: &Token, results: &mut Vec<u8>) -> Result<(), AssemblerError> {
+	let value = match t {
+	    Token::Float { value } => *value,
+	    Token::Pos { value } | Token::Bits { value } => *value as f64,
+	    Token::Neg { value } => *value as f64,
+	    _ => return Err(AssemblerError::NonNumberInFloatField),
+	};
+	write_float(value, results);
+	Ok(())
     }
-    fn extract_operand(t: &Token, results: &mut Vec<u8>) {
-	match t {
-            Token::Register { reg_num } => {
+
+    fn extract_operand(t: &Token, code: Opcode, kind: OperandKind, results: &mut Vec<u8>, symbols: &SymbolTable) -> Result<(), AssemblerError> {
+	match (t, kind) {
+	    (_, OperandKind::Float) => {
+		AssemblerInstruction::extract_float(t, results)?;
+	    },
+	    (Token::Register { reg_num }, _) => {
 		results.push(*reg_num);
-            }
-            Token::Pos { value } => {
-		let converted = *value;
-		let byte1 = converted;
-		let byte2 = converted >> 8;
-		let byte3 = converted >> 16;
-		let byte4 = converted >> 24;
-		let byte5 = converted >> 32;
-		let byte6 = converted >> 40;
-		let byte7 = converted >> 48;
-		let byte8 = converted >> 56;
-		results.push(byte8 as u8);
-		results.push(byte7 as u8);
-		results.push(byte6 as u8);
-		results.push(byte5 as u8);
-		results.push(byte4 as u8);
-		results.push(byte3 as u8);
-		results.push(byte2 as u8);
-		results.push(byte1 as u8);
-            },
-            Token::Neg { value } => {
-		let converted = (*value).abs() as u64;
-		let byte1 = converted;
-		let byte2 = converted >> 8;
-		let byte3 = converted >> 16;
-		let byte4 = converted >> 24;
-		let byte5 = converted >> 32;
-		let byte6 = converted >> 40;
-		let byte7 = converted >> 48;
-		let byte8 = ((converted >> 56) | (1 << 63)) >> 56;
-		results.push(byte8 as u8);
-		results.push(byte7 as u8);
-		results.push(byte6 as u8);
-		results.push(byte5 as u8);
-		results.push(byte4 as u8);
-		results.push(byte3 as u8);
-		results.push(byte2 as u8);
-		results.push(byte1 as u8);
-            },
-            _ => {
-		println!("Opcode found in operand field");
-		std::process::exit(1);
-            }
+	    },
+	    // Hex and binary literals are bit patterns, so 0xffffffffffffffff is -1
+	    // here, but a decimal literal has to mean the number it spells
+	    (Token::Pos { value }, OperandKind::Int) if *value > i64::MAX as u64 => {
+		return Err(AssemblerError::IntegerOutOfRange { literal: value.to_string() });
+	    },
+	    (Token::Pos { value }, OperandKind::Int) | (Token::Bits { value }, OperandKind::Int) => write_sleb(*value as i64, results),
+	    (Token::Neg { value }, OperandKind::Int) => write_sleb(*value, results),
+	    (Token::Pos { value }, _) | (Token::Bits { value }, _) => write_uleb(*value, results),
+	    (Token::Neg { value }, _) => {
+		return Err(AssemblerError::IntegerOutOfRange { literal: value.to_string() });
+	    },
+	    (Token::LabelUsage { name }, _) => {
+		let value = match symbols.symbol(name) {
+		    Some(symbol) if AssemblerInstruction::accepts_symbol(code, symbol.symbol_type) => symbol.offset,
+		    Some(symbol) => return Err(AssemblerError::WrongSymbolType {
+			name: name.clone(),
+			symbol_type: symbol.symbol_type,
+			mnemonic: code.mnemonic().to_string(),
+		    }),
+		    None => return Err(AssemblerError::UnknownLabel { name: name.clone() }),
+		};
+		let encoded = if kind == OperandKind::Int { zigzag(value as i64) } else { value };
+		if !write_uleb_padded(encoded, LABEL_LEN, results) {
+		    return Err(AssemblerError::LabelOutOfRange { name: name.clone() });
+		}
+	    },
+	    (Token::Float { .. }, _) => {
+		return Err(AssemblerError::FloatInIntegerField);
+	    },
+	    _ => {
+		return Err(AssemblerError::OpcodeInOperandField);
+	    }
 	};
+	Ok(())
     }
 }
 
 named!(instruction_combined<CompleteStr, AssemblerInstruction>,
     do_parse!(
+	l: opt!(label_declaration) >>
         o: opcode >>
         o1: opt!(operand) >>
         o2: opt!(operand) >>
@@ -94,7 +204,9 @@ named!(instruction_combined<CompleteStr, AssemblerInstruction>,
 	opt!(multispace) >>    
         (
             AssemblerInstruction{
-                opcode: o,
+		opcode: Some(o),
+		directive: None,
+		label: l,
                 operand1: o1,
                 operand2: o2,
                 operand3: o3,
@@ -119,7 +231,7 @@ named!(pub instruction<CompleteStr, AssemblerInstruction>,
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::instruction::Opcode;
+    use crate::assembler::symbols::{Symbol, SymbolType};
 
     #[test]
     fn test_parse_instruction_form_one() {
@@ -129,7 +241,9 @@ mod tests {
             Ok((
                 CompleteStr(""),
                 AssemblerInstruction {
-                    opcode: Token::Op { code: Opcode::Load },
+		    opcode: Some(Token::Op { code: Opcode::Load }),
+		    directive: None,
+		    label: None,
                     operand1: Some(Token::Register { reg_num: 0 }),
                     operand2: Some(Token::Pos { value: 100 }),
                     operand3: None
@@ -145,7 +259,9 @@ mod tests {
             Ok((
 		CompleteStr(""),
 		AssemblerInstruction {
-                    opcode: Token::Op { code: Opcode::Hlt },
+		    opcode: Some(Token::Op { code: Opcode::Hlt }),
+		    directive: None,
+		    label: None,
                     operand1: None,
                     operand2: None,
                     operand3: None
@@ -162,7 +278,9 @@ mod tests {
             Ok((
 		CompleteStr(""),
 		AssemblerInstruction {
-                    opcode: Token::Op { code: Opcode::Add },
+		    opcode: Some(Token::Op { code: Opcode::Add }),
+		    directive: None,
+		    label: None,
                     operand1: Some(Token::Register {reg_num: 0}),
                     operand2: Some(Token::Register {reg_num: 1}),
                     operand3: Some(Token::Register {reg_num: 2}),
@@ -170,4 +288,61 @@ mod tests {
             ))
 	);
     }
+
+    #[test]
+    fn test_parse_instruction_with_label() {
+	let result = instruction(CompleteStr("start: jmp r0\n"));
+	assert_eq!(
+	    result,
+	    Ok((
+		CompleteStr(""),
+		AssemblerInstruction {
+		    opcode: Some(Token::Op { code: Opcode::Jmp }),
+		    directive: None,
+		    label: Some(Token::LabelDeclaration { name: "start".to_string() }),
+		    operand1: Some(Token::Register { reg_num: 0 }),
+		    operand2: None,
+		    operand3: None,
+		}
+	    ))
+	);
+    }
+
+    #[test]
+    fn test_label_usage_to_bytes() {
+	let (_, ins) = instruction(CompleteStr("load r0 @end\n")).unwrap();
+	assert_eq!(ins.byte_len(), 7);
+	assert_eq!(
+	    ins.to_bytes(&SymbolTable::new()),
+	    Err(AssemblerError::UnknownLabel { name: "end".to_string() })
+	);
+	let mut symbols = SymbolTable::new();
+	symbols.add_symbol(Symbol::new("end".to_string(), SymbolType::Label, 300));
+	// 300 zigzags to 600, padded out to five bytes
+	assert_eq!(ins.to_bytes(&symbols), Ok(vec![1, 0, 0xd8, 0x84, 0x80, 0x80, 0x00]));
+    }
+
+    #[test]
+    fn test_operands_checked_against_opcode() {
+	let to_bytes = |source: &str| instruction(CompleteStr(source)).unwrap().1.to_bytes(&SymbolTable::new());
+	assert_eq!(
+	    to_bytes("add r0 r1\n"),
+	    Err(AssemblerError::WrongOperandCount { mnemonic: "add".to_string(), expected: 3, found: 2 })
+	);
+	assert_eq!(
+	    to_bytes("hlt r0\n"),
+	    Err(AssemblerError::WrongOperandCount { mnemonic: "hlt".to_string(), expected: 0, found: 1 })
+	);
+	assert_eq!(
+	    to_bytes("load r0 r1\n"),
+	    Err(AssemblerError::WrongOperandKind { mnemonic: "load".to_string(), position: 2, expected: OperandKind::Int })
+	);
+	assert_eq!(
+	    to_bytes("add r0 r1 300\n"),
+	    Err(AssemblerError::WrongOperandKind { mnemonic: "add".to_string(), position: 3, expected: OperandKind::Register })
+	);
+	let (_, ins) = instruction(CompleteStr("load r0 500 r1\n")).unwrap();
+	assert_eq!(ins.byte_len(), 0);
+	assert_eq!(to_bytes("add r0 r1 r2\n"), Ok(vec![Opcode::Add as u8, 0, 1, 2]));
+    }
 }
@@ -3,7 +3,7 @@ use nom::{alphanumeric, multispace};
 
 use crate::assembler::Token;
 
-/// Looks for a user-defined label, such as `label1:`
+// Looks for a user-defined label, such as `label1:`
 named!(pub label_declaration<CompleteStr, Token>,
     ws!(
         do_parse!(
@@ -17,7 +17,7 @@ named!(pub label_declaration<CompleteStr, Token>,
     )
 );
 
-/// Looks for a user-defined label, such as `label1:`
+// Looks for a reference to a user-defined label, such as `@label1`
 named!(pub label_usage<CompleteStr, Token>,
     ws!(
         do_parse!(
@@ -30,3 +30,28 @@ named!(pub label_usage<CompleteStr, Token>,
         )
     )
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_label_declaration() {
+	let result = label_declaration(CompleteStr("test:"));
+	assert!(result.is_ok());
+	let (_, token) = result.unwrap();
+	assert_eq!(token, Token::LabelDeclaration { name: "test".to_string() });
+	let result = label_declaration(CompleteStr("test"));
+	assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_label_usage() {
+	let result = label_usage(CompleteStr("@test"));
+	assert!(result.is_ok());
+	let (_, token) = result.unwrap();
+	assert_eq!(token, Token::LabelUsage { name: "test".to_string() });
+	let result = label_usage(CompleteStr("test"));
+	assert!(result.is_err());
+    }
+}
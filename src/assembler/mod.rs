@@ -1,10 +1,24 @@
+use nom::types::CompleteStr;
+use nom::{Context, Err, ErrorKind};
+
+use crate::bytecode::{Binary, MAX_SYMBOL_NAME_LEN};
 use crate::instruction::Opcode;
+use crate::vm::Val;
+use crate::assembler::assembler_errors::AssemblerError;
+use crate::assembler::instruction_parsers::AssemblerInstruction;
+use crate::assembler::operand_parsers::INTEGER_OUT_OF_RANGE;
+use crate::assembler::program_parsers::{program, Program};
+use crate::assembler::symbols::{Symbol, SymbolTable, SymbolType};
 
 pub mod opcode_parsers;
 pub mod register_parsers;
 pub mod operand_parsers;
 pub mod instruction_parsers;
 pub mod program_parsers;
+pub mod directive_parsers;
+pub mod label_parsers;
+pub mod symbols;
+pub mod assembler_errors;
 
 #[derive(Debug, PartialEq)]
 pub enum Token {
@@ -12,4 +26,450 @@ pub enum Token {
     Register{reg_num: u8},
     Neg{value: i64},
     Pos{value: u64},
+    /// A hex or binary literal, kept as a bit pattern so it can fill all 64 bits
+    Bits{value: u64},
+    Float{value: f64},
+    LabelDeclaration{name: String},
+    LabelUsage{name: String},
+    Directive{name: String},
+    IrString{name: String},
+}
+
+/// The label execution starts from in an assembled binary
+pub const ENTRY_POINT: &str = "main";
+
+/// Which part of the output the assembler is currently filling in
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum AssemblerSection {
+    Code,
+    Data,
+}
+
+/// Turns assembly source into bytecode in two passes: the first records where
+/// every label lives, the second emits bytes with `@label` references resolved.
+///
+/// Instructions go into the code section, which is where assembly starts.
+/// After `.data`, the `.asciiz`, `.int` and `.ptr` directives fill heap blocks
+/// instead; a label starts a new block and resolves to that block's id, which
+/// is its index in `data`. `.code` switches back.
+///
+/// `.extern name` may appear in either section. It declares a host function,
+/// numbered in declaration order, for `callnative @name` to call.
+#[derive(Debug)]
+pub struct Assembler {
+    pub symbols: SymbolTable,
+    pub data: Vec<Vec<Val>>,
+    section: AssemblerSection,
+    errors: Vec<AssemblerError>,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+	Assembler::new()
+    }
+}
+
+impl Assembler {
+    pub fn new() -> Assembler {
+	Assembler {
+	    symbols: SymbolTable::new(),
+	    data: vec![],
+	    section: AssemblerSection::Code,
+	    errors: vec![],
+	}
+    }
+
+    /// Assembles `raw` into code. Symbols and data from an earlier call are
+    /// discarded, so one `Assembler` can be reused for several programs.
+    pub fn assemble(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
+	self.assemble_at(raw, 0, SymbolTable::new())
+    }
+
+    /// Assembles `raw` as code to be appended at address `origin` of a program
+    /// that already declares `symbols`, as the REPL does line by line. Labels
+    /// get their address in the whole program and may refer back to
+    /// `symbols`, which `self.symbols` starts from.
+    pub fn assemble_at(&mut self, raw: &str, origin: u64, symbols: SymbolTable) -> Result<Vec<u8>, Vec<AssemblerError>> {
+	self.symbols = symbols;
+	self.data.clear();
+	self.errors.clear();
+	let program = match program(CompleteStr(raw)) {
+	    Ok((remainder, program)) => {
+		if !remainder.trim().is_empty() {
+		    return Err(vec![AssemblerError::ParseError { error: format!("unexpected input: {}", remainder.trim()) }]);
+		}
+		program
+	    },
+	    Err(Err::Failure(Context::Code(rest, ErrorKind::Custom(INTEGER_OUT_OF_RANGE)))) => {
+		let literal = rest.split_whitespace().next().unwrap_or_default();
+		return Err(vec![AssemblerError::IntegerOutOfRange { literal: literal.to_string() }]);
+	    },
+	    Err(e) => {
+		return Err(vec![AssemblerError::ParseError { error: format!("{:?}", e) }]);
+	    }
+	};
+	self.process_first_phase(&program, origin);
+	if !self.errors.is_empty() {
+	    return Err(self.errors.clone());
+	}
+	let code = self.process_second_phase(&program);
+	if !self.errors.is_empty() {
+	    return Err(self.errors.clone());
+	}
+	Ok(code)
+    }
+
+    /// Assembles `raw` into a complete bytecode file, as described in
+    /// `crate::bytecode`. Execution starts at the `main` label if there is one,
+    /// otherwise at the start of the code section.
+    pub fn assemble_binary(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
+	let code = self.assemble(raw)?;
+	let entry = match self.symbols.symbol(ENTRY_POINT) {
+	    Some(symbol) if symbol.symbol_type == SymbolType::Label => symbol.offset,
+	    _ => 0,
+	};
+	let binary = Binary {
+	    entry,
+	    code,
+	    data: self.data.clone(),
+	    symbols: self.symbols.clone(),
+	};
+	Ok(binary.to_bytes())
+    }
+
+    /// Walks the program recording the address of every label declaration
+    fn process_first_phase(&mut self, p: &Program, origin: u64) {
+	let mut offset = origin;
+	let mut blocks = 0;
+	let mut externs = 0;
+	let mut block_open = false;
+	self.section = AssemblerSection::Code;
+	for i in &p.instructions {
+	    let value = match i.directive_name() {
+		Some("code") | Some("data") => {
+		    self.switch_section(i);
+		    block_open = false;
+		    if let Some(name) = i.label_name() {
+			self.errors.push(AssemblerError::MisplacedLabel { name: name.to_string() });
+		    }
+		    continue;
+		},
+		Some(name @ "asciiz") | Some(name @ "int") | Some(name @ "ptr") | Some(name @ "float") => {
+		    if self.section != AssemblerSection::Data {
+			self.errors.push(AssemblerError::DataOutsideDataSection { directive: name.to_string() });
+			continue;
+		    }
+		    if i.label.is_some() || !block_open {
+			blocks += 1;
+			block_open = true;
+		    }
+		    blocks - 1
+		},
+		Some("extern") => {
+		    match i.label_name() {
+			Some(name) if i.operands().next().is_none() => self.declare_symbol(name, SymbolType::Extern, externs),
+			_ => self.errors.push(AssemblerError::InvalidDirectiveOperand { directive: "extern".to_string() }),
+		    }
+		    externs += 1;
+		    continue;
+		},
+		Some(name) => {
+		    self.errors.push(AssemblerError::UnknownDirective { name: name.to_string() });
+		    continue;
+		},
+		None => {
+		    if self.section != AssemblerSection::Code {
+			self.errors.push(AssemblerError::InstructionOutsideCodeSection);
+			continue;
+		    }
+		    offset += i.byte_len();
+		    offset - i.byte_len()
+		}
+	    };
+	    if let Some(name) = i.label_name() {
+		let symbol_type = match self.section {
+		    AssemblerSection::Code => SymbolType::Label,
+		    AssemblerSection::Data => SymbolType::Data,
+		};
+		self.declare_symbol(name, symbol_type, value);
+	    }
+	}
+    }
+
+    fn declare_symbol(&mut self, name: &str, symbol_type: SymbolType, value: u64) {
+	if name.len() > MAX_SYMBOL_NAME_LEN {
+	    self.errors.push(AssemblerError::SymbolNameTooLong { length: name.len() });
+	} else if self.symbols.has_symbol(name) {
+	    self.errors.push(AssemblerError::SymbolAlreadyDeclared { name: name.to_string() });
+	} else {
+	    self.symbols.add_symbol(Symbol::new(name.to_string(), symbol_type, value));
+	}
+    }
+
+    /// Emits the code section and fills `data`, now that every symbol is known
+    fn process_second_phase(&mut self, p: &Program) -> Vec<u8> {
+	let mut code = vec![];
+	let mut block_open = false;
+	self.section = AssemblerSection::Code;
+	self.data.clear();
+	for i in &p.instructions {
+	    match i.directive_name() {
+		Some("code") | Some("data") => {
+		    self.switch_section(i);
+		    block_open = false;
+		},
+		Some("extern") => {},
+		Some(directive) => {
+		    if i.label.is_some() || !block_open {
+			self.data.push(vec![]);
+			block_open = true;
+		    }
+		    for operand in i.operands() {
+			match Assembler::data_cells(directive, operand, &self.symbols) {
+			    Ok(mut cells) => self.data.last_mut().unwrap().append(&mut cells),
+			    Err(e) => self.errors.push(e),
+			}
+		    }
+		},
+		None => {
+		    match i.to_bytes(&self.symbols) {
+			Ok(mut bytes) => code.append(&mut bytes),
+			Err(e) => self.errors.push(e),
+		    }
+		}
+	    }
+	}
+	code
+    }
+
+    fn switch_section(&mut self, i: &AssemblerInstruction) {
+	match i.directive_name() {
+	    Some("code") => self.section = AssemblerSection::Code,
+	    Some("data") => self.section = AssemblerSection::Data,
+	    _ => {}
+	}
+    }
+
+    /// Converts one operand of a data directive into the heap cells it stands for
+    fn data_cells(directive: &str, operand: &Token, symbols: &SymbolTable) -> Result<Vec<Val>, AssemblerError> {
+	match (directive, operand) {
+	    ("asciiz", Token::IrString { name }) => {
+		let mut cells: Vec<Val> = name.bytes().map(|b| Val::Int(i64::from(b))).collect();
+		cells.push(Val::Int(0));
+		Ok(cells)
+	    },
+	    ("int", Token::Pos { value }) if *value > i64::MAX as u64 => {
+		Err(AssemblerError::IntegerOutOfRange { literal: value.to_string() })
+	    },
+	    ("int", Token::Pos { value }) | ("int", Token::Bits { value }) => Ok(vec![Val::Int(*value as i64)]),
+	    ("int", Token::Neg { value }) => Ok(vec![Val::Int(*value)]),
+	    ("float", Token::Float { value }) => Ok(vec![Val::Float(*value)]),
+	    ("float", Token::Pos { value }) | ("float", Token::Bits { value }) => Ok(vec![Val::Float(*value as f64)]),
+	    ("float", Token::Neg { value }) => Ok(vec![Val::Float(*value as f64)]),
+	    ("ptr", Token::Pos { value }) | ("ptr", Token::Bits { value }) => Ok(vec![Val::Ptr(*value)]),
+	    ("ptr", Token::LabelUsage { name }) => {
+		match symbols.symbol_value(name) {
+		    Some(value) => Ok(vec![Val::Ptr(value)]),
+		    None => Err(AssemblerError::UnknownLabel { name: name.clone() }),
+		}
+	    },
+	    _ => Err(AssemblerError::InvalidDirectiveOperand { directive: directive.to_string() }),
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{RunStatus, VM};
+
+    #[test]
+    fn test_assemble_program() {
+	let mut asm = Assembler::new();
+	let test_string = "load r0 @done\njmp r0\nload r1 5\ndone: load r2 7\nhlt\n";
+	let program = asm.assemble(test_string).unwrap();
+	assert_eq!(asm.symbols.symbol_value("done"), Some(12));
+	let mut vm = VM::new();
+	vm.program = program;
+	vm.run().unwrap();
+	assert_eq!(vm.registers[1], Val::Int(0));
+	assert_eq!(vm.registers[2], Val::Int(7));
+    }
+
+    #[test]
+    fn test_undefined_and_duplicate_labels() {
+	let mut asm = Assembler::new();
+	assert_eq!(
+	    asm.assemble("load r0 @nowhere\nhlt\n"),
+	    Err(vec![AssemblerError::UnknownLabel { name: "nowhere".to_string() }])
+	);
+	let mut asm = Assembler::new();
+	assert_eq!(
+	    asm.assemble("a: hlt\na: hlt\n"),
+	    Err(vec![AssemblerError::SymbolAlreadyDeclared { name: "a".to_string() }])
+	);
+	// Neither symbols nor errors carry over to the next program
+	assert_eq!(asm.assemble("a: hlt\n"), Ok(vec![0]));
+	assert_eq!(asm.assemble("a: load r0 1\n").map(|code| code.len()), Ok(3));
+	assert_eq!(asm.symbols.symbols().len(), 1);
+	let name = "a".repeat(MAX_SYMBOL_NAME_LEN + 1);
+	assert_eq!(asm.assemble(&format!("{}: hlt\n", name)), Err(vec![AssemblerError::SymbolNameTooLong { length: MAX_SYMBOL_NAME_LEN + 1 }]));
+    }
+
+    #[test]
+    fn test_data_section() {
+	let mut asm = Assembler::new();
+	let test_string = ".data\nwname: \"world\"\nnums: .int 1 -2\n.int 3\nrefs: .ptr @nums\nfs: .float 1.5 -2\n.code\nloadptr r0 @wname\nload r1 1\nderef r0 r1 r2\nhlt\n";
+	let code = asm.assemble(test_string).unwrap();
+	assert_eq!(asm.symbols.symbol_value("wname"), Some(0));
+	assert_eq!(asm.symbols.symbol_value("nums"), Some(1));
+	assert_eq!(asm.data.len(), 4);
+	assert_eq!(asm.data[1], vec![Val::Int(1), Val::Int(-2), Val::Int(3)]);
+	assert_eq!(asm.data[2], vec![Val::Ptr(1)]);
+	assert_eq!(asm.data[3], vec![Val::Float(1.5), Val::Float(-2.0)]);
+	let mut vm = VM::new();
+	vm.load_data(&asm.data);
+	vm.program = code;
+	vm.run().unwrap();
+	assert_eq!(vm.registers[2], Val::Int(i64::from(b'o')));
+    }
+
+    #[test]
+    fn test_section_errors() {
+	let mut asm = Assembler::new();
+	assert_eq!(
+	    asm.assemble("x: .int 5\n"),
+	    Err(vec![AssemblerError::DataOutsideDataSection { directive: "int".to_string() }])
+	);
+	let mut asm = Assembler::new();
+	assert_eq!(
+	    asm.assemble(".data\nhlt\n"),
+	    Err(vec![AssemblerError::InstructionOutsideCodeSection])
+	);
+	let mut asm = Assembler::new();
+	assert_eq!(
+	    asm.assemble(".bogus\n"),
+	    Err(vec![AssemblerError::UnknownDirective { name: "bogus".to_string() }])
+	);
+    }
+
+    #[test]
+    fn test_assemble_binary() {
+	let mut asm = Assembler::new();
+	let test_string = ".data\nanswer: .int 42\n.code\nhelper: load r1 1\nret\nmain: loadptr r0 @answer\nload r3 @helper\ncall r3\nderef r0 r2 r2\nhlt\n";
+	let bytes = asm.assemble_binary(test_string).unwrap();
+	let mut vm = VM::new();
+	vm.load_bytecode(&bytes).unwrap();
+	assert_eq!(vm.symbols.symbol_value("main"), Some(4));
+	vm.run().unwrap();
+	assert_eq!(vm.registers[1], Val::Int(1));
+	assert_eq!(vm.registers[2], Val::Int(42));
+    }
+
+    #[test]
+    fn test_float_operands() {
+	let mut asm = Assembler::new();
+	let code = asm.assemble("loadf r0 -2.5e3\nloadf r1 2\nitof r2 r1\nhlt\n").unwrap();
+	let mut vm = VM::new();
+	vm.program = code;
+	vm.run().unwrap();
+	assert_eq!(vm.registers[0], Val::Float(-2500.0));
+	assert_eq!(vm.registers[1], Val::Float(2.0));
+	let mut asm = Assembler::new();
+	assert_eq!(asm.assemble("load r0 1.5\n"), Err(vec![AssemblerError::FloatInIntegerField]));
+	let mut asm = Assembler::new();
+	assert_eq!(asm.assemble("loadf r0 r1\n"), Err(vec![AssemblerError::NonNumberInFloatField]));
+    }
+
+    #[test]
+    fn test_integer_ranges() {
+	let mut asm = Assembler::new();
+	let code = asm.assemble("load r0 -9_223_372_036_854_775_808\nload r1 0xffff_ffff_ffff_ffff\nloadptr r2 18446744073709551615\nload r3 0b1010\nhlt\n").unwrap();
+	let mut vm = VM::new();
+	vm.program = code;
+	vm.run().unwrap();
+	assert_eq!(vm.registers[0], Val::Int(i64::MIN));
+	assert_eq!(vm.registers[1], Val::Int(-1));
+	assert_eq!(vm.registers[2], Val::Ptr(u64::MAX));
+	assert_eq!(vm.registers[3], Val::Int(10));
+	let mut asm = Assembler::new();
+	assert_eq!(asm.assemble("load r0 9223372036854775808\n"), Err(vec![AssemblerError::IntegerOutOfRange { literal: "9223372036854775808".to_string() }]));
+	let mut asm = Assembler::new();
+	assert_eq!(asm.assemble("loadptr r0 -1\n"), Err(vec![AssemblerError::IntegerOutOfRange { literal: "-1".to_string() }]));
+	let mut asm = Assembler::new();
+	assert_eq!(asm.assemble("hlt\nload r0 0x1_0000_0000_0000_0000\n"), Err(vec![AssemblerError::IntegerOutOfRange { literal: "0x1_0000_0000_0000_0000".to_string() }]));
+	let mut asm = Assembler::new();
+	assert_eq!(asm.assemble(".data\nbig: .int 18446744073709551615\n"), Err(vec![AssemblerError::IntegerOutOfRange { literal: "18446744073709551615".to_string() }]));
+    }
+
+    #[test]
+    fn test_extern_directive() {
+	let mut asm = Assembler::new();
+	let code = asm.assemble(".extern print\n.data\nname: .asciiz 'x'\nread: .extern\n.code\ncallnative @read\n").unwrap();
+	assert_eq!(code, vec![65, 0x81, 0x80, 0x80, 0x80, 0x00]);
+	assert_eq!(asm.symbols.symbol("read"), Some(&Symbol::new("read".to_string(), SymbolType::Extern, 1)));
+	assert_eq!(asm.data.len(), 1);
+	let mut asm = Assembler::new();
+	assert_eq!(asm.assemble(".extern print\n.extern print\n"), Err(vec![AssemblerError::SymbolAlreadyDeclared { name: "print".to_string() }]));
+	let mut asm = Assembler::new();
+	assert_eq!(asm.assemble("print: .extern 1\n"), Err(vec![AssemblerError::InvalidDirectiveOperand { directive: "extern".to_string() }]));
+    }
+
+    #[test]
+    fn test_symbol_types() {
+	let prelude = ".extern println\n.data\ngreeting: .asciiz 'hi'\n.code\nmain: ";
+	let wrong = |name: &str, symbol_type, mnemonic: &str| Err(vec![AssemblerError::WrongSymbolType { name: name.to_string(), symbol_type, mnemonic: mnemonic.to_string() }]);
+	let mut asm = Assembler::new();
+	assert_eq!(asm.assemble(&format!("{}callnative @main\n", prelude)), wrong("main", SymbolType::Label, "callnative"));
+	assert_eq!(asm.assemble(&format!("{}jmpi @println\n", prelude)), wrong("println", SymbolType::Extern, "jmpi"));
+	assert_eq!(asm.assemble(&format!("{}call @greeting\n", prelude)), wrong("greeting", SymbolType::Data, "calli"));
+	assert_eq!(asm.assemble(&format!("{}loadptr r0 @main\n", prelude)), wrong("main", SymbolType::Label, "loadptr"));
+	assert_eq!(asm.assemble(&format!("{}load r0 @println\n", prelude)), wrong("println", SymbolType::Extern, "load"));
+	assert!(asm.assemble(&format!("{}callnative @println\nloadptr r0 @greeting\nload r1 @main\njmpi @main\n", prelude)).is_ok());
+	assert_eq!(AssemblerError::WrongSymbolType { name: "main".to_string(), symbol_type: SymbolType::Label, mnemonic: "callnative".to_string() }.to_string(),
+		   "main is a code label, which callnative cannot take");
+    }
+
+    #[test]
+    fn test_assemble_at() {
+	let mut asm = Assembler::new();
+	let mut program = asm.assemble("load r0 1\n").unwrap();
+	let symbols = asm.symbols.clone();
+	let mut line = asm.assemble_at("top: addi r0 r0 1\n", program.len() as u64, symbols).unwrap();
+	program.append(&mut line);
+	assert_eq!(asm.symbols.symbol_value("top"), Some(3));
+	let symbols = asm.symbols.clone();
+	let mut line = asm.assemble_at("jmp @top\n", program.len() as u64, symbols).unwrap();
+	program.append(&mut line);
+	assert_eq!(asm.assemble_at("top: hlt\n", program.len() as u64, asm.symbols.clone()),
+		   Err(vec![AssemblerError::SymbolAlreadyDeclared { name: "top".to_string() }]));
+	let mut vm = VM::new();
+	vm.program = program;
+	assert_eq!(vm.run_for(7), RunStatus::OutOfFuel);
+	assert_eq!(vm.registers[0], Val::Int(4));
+    }
+
+    #[test]
+    fn test_bitwise_mnemonics() {
+	let mut asm = Assembler::new();
+	let code = asm.assemble("and r0 r1 r2\nor r0 r1 r2\nxor r0 r1 r2\nnot r0 r1\nshl r0 r1 r2\nshr r0 r1 r2\nsar r0 r1 r2\n").unwrap();
+	assert_eq!(code, vec![31, 0, 1, 2, 32, 0, 1, 2, 33, 0, 1, 2, 34, 0, 1, 35, 0, 1, 2, 36, 0, 1, 2, 37, 0, 1, 2]);
+    }
+
+    #[test]
+    fn test_immediate_forms() {
+	let mut asm = Assembler::new();
+	let test_string = "load r1 5\nloop: addi r0 r0 1\ncmp r0 r1\njlt @loop\ncall @done\nhlt\ndone: mov r2 r0\nret\n";
+	let code = asm.assemble(test_string).unwrap();
+	assert_eq!(code[3], Opcode::AddI as u8);
+	assert_eq!(code[10], Opcode::JltI as u8);
+	assert_eq!(code[16], Opcode::CallI as u8);
+	let mut vm = VM::new();
+	vm.program = code;
+	vm.run().unwrap();
+	assert_eq!(vm.registers[0], Val::Int(5));
+	assert_eq!(vm.registers[2], Val::Int(5));
+	let mut asm = Assembler::new();
+	assert_eq!(asm.assemble("jmp r3\n").unwrap(), vec![Opcode::Jmp as u8, 3]);
+    }
 }
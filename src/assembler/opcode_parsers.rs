@@ -13,6 +13,7 @@ named!(pub opcode<CompleteStr, Token>,
        )
 );
 
+#[cfg(test)]
 mod tests {
     use super::*;
 
@@ -20,7 +21,7 @@ mod tests {
     fn test_opcode_load() {
         // First tests that the opcode is detected and parsed correctly
         let result = opcode(CompleteStr("load"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (rest, token) = result.unwrap();
         assert_eq!(token, Token::Op{code: Opcode::Load});
         assert_eq!(rest, CompleteStr(""));
@@ -1,70 +1,194 @@
 use nom::types::CompleteStr;
-use nom::digit;
+use nom::{digit, Context, Err, ErrorKind, IResult};
 
 use crate::assembler::Token;
+use crate::assembler::label_parsers::label_usage;
 use crate::assembler::register_parsers::register;
 
-named!(pub pos<CompleteStr, Token>,
-       ws!(
-           do_parse!(
-	       num: digit >>
-		   (
-		       Token::Pos{value: num.parse::<u64>().unwrap()}
-			   )
-           )
+/// Code of the parse failure raised for an integer literal that does not fit
+/// in 64 bits. The failure points at the start of the literal.
+pub const INTEGER_OUT_OF_RANGE: u32 = 1;
+
+fn is_decimal_digit(c: char) -> bool {
+    c.is_ascii_digit() || c == '_'
+}
+
+fn is_hex_digit(c: char) -> bool {
+    c.is_ascii_hexdigit() || c == '_'
+}
+
+fn is_binary_digit(c: char) -> bool {
+    c == '0' || c == '1' || c == '_'
+}
+
+// Digits may be separated by underscores, as in 1_000_000, but not start with one
+named!(decimal_digits<CompleteStr, CompleteStr>,
+       recognize!(tuple!(one_of!("0123456789"), take_while!(is_decimal_digit)))
+);
+named!(hex_digits<CompleteStr, CompleteStr>,
+       recognize!(tuple!(one_of!("0123456789abcdefABCDEF"), take_while!(is_hex_digit)))
+);
+named!(binary_digits<CompleteStr, CompleteStr>,
+       recognize!(tuple!(one_of!("01"), take_while!(is_binary_digit)))
+);
+// An optional minus sign, then the base and digits of the literal
+named!(integer_literal<CompleteStr, (bool, u32, CompleteStr)>,
+       do_parse!(
+	   sign: opt!(tag!("-")) >>
+	   digits: alt!(
+	       preceded!(tag_no_case!("0x"), hex_digits) => { |d| (16, d) } |
+	       preceded!(tag_no_case!("0b"), binary_digits) => { |d| (2, d) } |
+	       decimal_digits => { |d| (10, d) }
+	   ) >>
+	   (
+	       (sign.is_some(), digits.0, digits.1)
+	   )
+       )
+);
+
+/// Builds the token for a literal, or None if it does not fit in 64 bits.
+/// Positive literals may use the whole `u64` range and negative ones reach
+/// down to `i64::MIN`.
+fn integer_token(negative: bool, base: u32, digits: &str) -> Option<Token> {
+    let digits: String = digits.chars().filter(|c| *c != '_').collect();
+    let magnitude = u64::from_str_radix(&digits, base).ok()?;
+    if negative {
+	if magnitude > i64::MIN.unsigned_abs() {
+	    return None;
+	}
+	Some(Token::Neg{value: (magnitude as i64).wrapping_neg()})
+    } else if base == 10 {
+	Some(Token::Pos{value: magnitude})
+    } else {
+	Some(Token::Bits{value: magnitude})
+    }
+}
+
+fn checked_integer(input: CompleteStr) -> IResult<CompleteStr, Token> {
+    let (rest, (negative, base, digits)) = integer_literal(input)?;
+    match integer_token(negative, base, &digits) {
+	Some(token) => Ok((rest, token)),
+	None => Err(Err::Failure(Context::Code(input, ErrorKind::Custom(INTEGER_OUT_OF_RANGE)))),
+    }
+}
+
+named!(pub integer_operand<CompleteStr, Token>,
+       ws!(call!(checked_integer))
+);
+
+named!(exponent<CompleteStr, CompleteStr>,
+       recognize!(tuple!(one_of!("eE"), opt!(one_of!("+-")), digit))
+);
+// A number with a fractional part, an exponent or both, such as 3.14 or -2.5e3
+named!(float_literal<CompleteStr, CompleteStr>,
+       recognize!(
+	   tuple!(
+	       opt!(tag!("-")),
+	       digit,
+	       alt!(
+		   recognize!(tuple!(tag!("."), digit, opt!(exponent))) |
+		   exponent
+	       )
+	   )
        )
 );
-named!(pub neg<CompleteStr, Token>,
+named!(pub float_operand<CompleteStr, Token>,
        ws!(
-           do_parse!(
-	       _s: tag!("-") >>
-	       num: digit >>
-		   (
-		       Token::Neg{value: -(num.parse::<i64>().unwrap())}
-			   )
-           )
+	   do_parse!(
+	       text: float_literal >>
+	       (
+		   Token::Float{value: text.parse::<f64>().unwrap()}
+	       )
+	   )
        )
 );
-named!(pub integer_operand<CompleteStr, Token>,
+
+named!(single_quoted<CompleteStr, CompleteStr>,
+       delimited!(tag!("'"), take_until!("'"), tag!("'"))
+);
+named!(double_quoted<CompleteStr, CompleteStr>,
+       delimited!(tag!("\""), take_until!("\""), tag!("\""))
+);
+// A string literal in either 'single' or "double" quotes
+named!(pub irstring<CompleteStr, Token>,
        ws!(
-           do_parse!(
-	       n: alt!(
-		   neg |
-		   pos
-	       ) >>
-		   (
-		       n
-		   )
-           )
+	   do_parse!(
+	       content: alt!(single_quoted | double_quoted) >>
+	       (
+		   Token::IrString{name: content.to_string()}
+	       )
+	   )
        )
 );
 
 named!(pub operand<CompleteStr, Token>,
     alt!(
+	float_operand |
         integer_operand |
-        register
+	register |
+	label_usage |
+	irstring
     )
 );
 
 
+#[cfg(test)]
 mod test {
     use super::*;
     #[test]
     fn test_parse_integer_operand() {
 	// Test a valid integer operand
 	let result = integer_operand(CompleteStr("10"));
-	assert_eq!(result.is_ok(), true);
+	assert!(result.is_ok());
 	let (rest, value) = result.unwrap();
 	assert_eq!(rest, CompleteStr(""));
 	assert_eq!(value, Token::Pos{value: 10});
 
 	let result = integer_operand(CompleteStr("-10"));
-	assert_eq!(result.is_ok(), true);
+	assert!(result.is_ok());
 	let (rest, value) = result.unwrap();
 	assert_eq!(rest, CompleteStr(""));
 	assert_eq!(value, Token::Neg{value: -10});
 	// Test an invalid one (missing the #)
 	let result = integer_operand(CompleteStr("#10"));
-	assert_eq!(result.is_ok(), false);
+	assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_integer_bases() {
+	assert_eq!(integer_operand(CompleteStr("0xFF")), Ok((CompleteStr(""), Token::Bits { value: 255 })));
+	assert_eq!(integer_operand(CompleteStr("0b1010")), Ok((CompleteStr(""), Token::Bits { value: 10 })));
+	assert_eq!(integer_operand(CompleteStr("-0x10")), Ok((CompleteStr(""), Token::Neg { value: -16 })));
+	assert_eq!(integer_operand(CompleteStr("1_000_000")), Ok((CompleteStr(""), Token::Pos { value: 1_000_000 })));
+	assert_eq!(integer_operand(CompleteStr("-9223372036854775808")), Ok((CompleteStr(""), Token::Neg { value: i64::MIN })));
+	assert_eq!(integer_operand(CompleteStr("18446744073709551615")), Ok((CompleteStr(""), Token::Pos { value: u64::MAX })));
+	assert!(integer_operand(CompleteStr("_1")).is_err());
+    }
+
+    #[test]
+    fn test_parse_integer_out_of_range() {
+	for literal in &["18446744073709551616", "-9223372036854775809", "0x1_0000_0000_0000_0000"] {
+	    let expected = Err(Err::Failure(Context::Code(CompleteStr(literal), ErrorKind::Custom(INTEGER_OUT_OF_RANGE))));
+	    assert_eq!(operand(CompleteStr(literal)), expected);
+	}
+    }
+
+    #[test]
+    fn test_parse_float_operand() {
+	assert_eq!(float_operand(CompleteStr("3.25")), Ok((CompleteStr(""), Token::Float { value: 3.25 })));
+	assert_eq!(float_operand(CompleteStr("-2.5e3")), Ok((CompleteStr(""), Token::Float { value: -2500.0 })));
+	assert_eq!(float_operand(CompleteStr("1e-2")), Ok((CompleteStr(""), Token::Float { value: 0.01 })));
+	assert!(float_operand(CompleteStr("10")).is_err());
+	assert_eq!(operand(CompleteStr("10")), Ok((CompleteStr(""), Token::Pos { value: 10 })));
+    }
+
+    #[test]
+    fn test_parse_string_operand() {
+	let result = irstring(CompleteStr("'Hello'"));
+	assert_eq!(result, Ok((CompleteStr(""), Token::IrString { name: "Hello".to_string() })));
+	let result = irstring(CompleteStr("\"world\""));
+	assert_eq!(result, Ok((CompleteStr(""), Token::IrString { name: "world".to_string() })));
+	let result = irstring(CompleteStr("'unterminated"));
+	assert!(result.is_err());
     }
 }
@@ -1,39 +1,58 @@
 use nom::types::CompleteStr;
+use nom::multispace;
 
+use crate::assembler::assembler_errors::AssemblerError;
+use crate::assembler::directive_parsers::directive;
 use crate::assembler::instruction_parsers::{AssemblerInstruction, instruction};
+use crate::assembler::symbols::SymbolTable;
 
 #[derive(Debug, PartialEq)]
 pub struct Program {
-    instructions: Vec<AssemblerInstruction>
+    pub instructions: Vec<AssemblerInstruction>
 }
 
 impl Program {
-    pub fn to_bytes(&self) -> Vec<u8> {
+    /// Emits bytecode, resolving label usages against `symbols`. Every
+    /// instruction that fails to encode is reported, not just the first.
+    pub fn to_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, Vec<AssemblerError>> {
         let mut program = vec![];
+	let mut errors = vec![];
         for instruction in &self.instructions {
-            program.append(&mut instruction.to_bytes());
+	    match instruction.to_bytes(symbols) {
+		Ok(mut bytes) => program.append(&mut bytes),
+		Err(e) => errors.push(e),
+	    }
+	}
+	if errors.is_empty() {
+	    Ok(program)
+	} else {
+	    Err(errors)
         }
-        program
     }
 }
 
 named!(pub program<CompleteStr, Program>,
        do_parse!(
-           instructions: many1!(instruction) >>
+	   opt!(multispace) >>
+	   // The first line is parsed on its own rather than with many1!, which
+	   // would replace the reason it failed with its own error
+	   first: alt!(instruction | directive) >>
+	   rest: many0!(alt!(instruction | directive)) >>
                (
 		   Program {
-                       instructions: instructions
+		       instructions: std::iter::once(first).chain(rest).collect()
 		   }
                )
        )
 );
 
+#[cfg(test)]
 mod test {
     use super::*;
     #[test]
     fn test_parse_program() {
 	let result = program(CompleteStr("load r0 100\n"));
-	assert_eq!(result.is_ok(), true);
+	assert!(result.is_ok());
 	let (leftover, p) = result.unwrap();
 	assert_eq!(leftover, CompleteStr(""));
 	assert_eq!(
@@ -46,10 +65,10 @@ mod test {
     #[test]
     fn test_program_to_bytes() {
 	let result = program(CompleteStr("load r0 100\n"));
-	assert_eq!(result.is_ok(), true);
+	assert!(result.is_ok());
 	let (_, program) = result.unwrap();
-	let bytecode = program.to_bytes();
-	assert_eq!(bytecode.len(), 10);
+	let bytecode = program.to_bytes(&SymbolTable::new()).unwrap();
+	assert_eq!(bytecode.len(), 4);
 	println!("{:?}", bytecode);
     }
 }
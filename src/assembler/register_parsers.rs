@@ -17,17 +17,18 @@ named!(pub register <CompleteStr, Token>,
        )
 );
 
+#[cfg(test)]
 mod test{
     use super::*;
     
     #[test]
     fn test_parse_register() {
 	let result = register(CompleteStr("r0"));
-	assert_eq!(result.is_ok(), true);
+	assert!(result.is_ok());
 	let result = register(CompleteStr("0"));
-	assert_eq!(result.is_ok(), false);
+	assert!(result.is_err());
 	let result = register(CompleteStr("ra"));
-	assert_eq!(result.is_ok(), false);
+	assert!(result.is_err());
     }
 
 }
@@ -0,0 +1,75 @@
+/// What kind of thing a symbol names
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SymbolType {
+    Label,
+    Data,
+    /// A host function declared with `.extern`; its value is the extern's index
+    Extern,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub offset: u64,
+    pub symbol_type: SymbolType,
+}
+
+impl Symbol {
+    pub fn new(name: String, symbol_type: SymbolType, offset: u64) -> Symbol {
+	Symbol {
+	    name,
+	    symbol_type,
+	    offset,
+	}
+    }
+}
+
+/// Every symbol the assembler has seen, in declaration order
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+	SymbolTable {
+	    symbols: vec![],
+	}
+    }
+
+    pub fn add_symbol(&mut self, s: Symbol) {
+	self.symbols.push(s);
+    }
+
+    pub fn has_symbol(&self, s: &str) -> bool {
+	self.symbols.iter().any(|symbol| symbol.name == s)
+    }
+
+    pub fn symbol(&self, s: &str) -> Option<&Symbol> {
+	self.symbols.iter().find(|symbol| symbol.name == s)
+    }
+
+    pub fn symbol_value(&self, s: &str) -> Option<u64> {
+	self.symbol(s).map(|symbol| symbol.offset)
+    }
+
+    pub fn symbols(&self) -> &[Symbol] {
+	&self.symbols
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbol_table() {
+	let mut sym = SymbolTable::new();
+	let new_symbol = Symbol::new("test".to_string(), SymbolType::Label, 12);
+	sym.add_symbol(new_symbol);
+	assert_eq!(sym.symbols().len(), 1);
+	assert!(sym.has_symbol("test"));
+	assert_eq!(sym.symbol_value("test"), Some(12));
+	assert_eq!(sym.symbol_value("does_not_exist"), None);
+    }
+}
@@ -0,0 +1,337 @@
+//! The on-disk format for assembled bedrock programs.
+//!
+//! Every file starts with a fixed 40 byte header, followed by the code
+//! section, the data section and the symbol table, in that order. All
+//! integers here are fixed width and big endian; immediates inside the code
+//! section are laid out as described in `encoding`.
+//!
+//! ```text
+//! offset  size  field
+//!      0     4  magic number, "BDRK"
+//!      4     2  format version
+//!      6     2  reserved, always 0
+//!      8     8  entry point, as an offset into the code section
+//!     16     8  code section length
+//!     24     8  data section length
+//!     32     8  symbol table length
+//! ```
+//!
+//! The data section is a block count followed by each block: a cell count and
+//! then one tag byte (0 for `Int`, 1 for `Ptr`, 2 for `Float`) and 8 value
+//! bytes per cell, floats being stored as their IEEE 754 bits.
+//! Blocks are loaded into the heap with their index as their id. The symbol
+//! table is a symbol count followed by each symbol: a type byte (0 for code
+//! labels, 1 for data labels, 2 for externs), a 2 byte name length, the UTF-8
+//! name and the 8 byte value.
+
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+
+use crate::assembler::symbols::{Symbol, SymbolTable, SymbolType};
+use crate::vm::Val;
+
+pub const MAGIC: [u8; 4] = *b"BDRK";
+pub const VERSION: u16 = 2;
+pub const HEADER_LEN: usize = 40;
+/// Longest symbol name the symbols section can hold, in bytes
+pub const MAX_SYMBOL_NAME_LEN: usize = u16::MAX as usize;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum LoadError {
+    BadMagic,
+    UnsupportedVersion { found: u16, supported: u16 },
+    Truncated,
+    Malformed { reason: String },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	match self {
+	    LoadError::BadMagic => write!(f, "not a bedrock binary (bad magic number)"),
+	    LoadError::UnsupportedVersion { found, supported } => write!(f, "bytecode format version {} is not supported (expected {})", found, supported),
+	    LoadError::Truncated => write!(f, "bytecode file is truncated"),
+	    LoadError::Malformed { reason } => write!(f, "malformed bytecode file: {}", reason),
+	}
+    }
+}
+
+impl error::Error for LoadError {}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Header {
+    pub version: u16,
+    pub entry: u64,
+    pub code_len: u64,
+    pub data_len: u64,
+    pub symbols_len: u64,
+}
+
+impl Header {
+    pub fn to_bytes(&self) -> Vec<u8> {
+	let mut bytes = Vec::with_capacity(HEADER_LEN);
+	bytes.extend_from_slice(&MAGIC);
+	bytes.extend_from_slice(&self.version.to_be_bytes());
+	bytes.extend_from_slice(&[0, 0]);
+	bytes.extend_from_slice(&self.entry.to_be_bytes());
+	bytes.extend_from_slice(&self.code_len.to_be_bytes());
+	bytes.extend_from_slice(&self.data_len.to_be_bytes());
+	bytes.extend_from_slice(&self.symbols_len.to_be_bytes());
+	bytes
+    }
+
+    /// Reads and validates the header at the start of `bytes`
+    pub fn parse(bytes: &[u8]) -> Result<Header, LoadError> {
+	if bytes.len() < MAGIC.len() || bytes[..MAGIC.len()] != MAGIC {
+	    return Err(LoadError::BadMagic);
+	}
+	let mut reader = Reader::new(&bytes[MAGIC.len()..]);
+	let version = reader.u16()?;
+	if version != VERSION {
+	    return Err(LoadError::UnsupportedVersion { found: version, supported: VERSION });
+	}
+	reader.u16()?;
+	let header = Header {
+	    version,
+	    entry: reader.u64()?,
+	    code_len: reader.u64()?,
+	    data_len: reader.u64()?,
+	    symbols_len: reader.u64()?,
+	};
+	let expected = (HEADER_LEN as u64)
+	    .checked_add(header.code_len)
+	    .and_then(|n| n.checked_add(header.data_len))
+	    .and_then(|n| n.checked_add(header.symbols_len));
+	match expected {
+	    Some(n) if n == bytes.len() as u64 => {},
+	    Some(n) if n > bytes.len() as u64 => return Err(LoadError::Truncated),
+	    _ => return Err(LoadError::Malformed { reason: "section lengths do not match the file size".to_string() }),
+	}
+	if header.entry > header.code_len {
+	    return Err(LoadError::Malformed { reason: format!("entry point {} is outside the code section", header.entry) });
+	}
+	Ok(header)
+    }
+}
+
+/// The sections of a bytecode file, decoded
+#[derive(Debug, PartialEq)]
+pub struct Binary {
+    pub entry: u64,
+    pub code: Vec<u8>,
+    pub data: Vec<Vec<Val>>,
+    pub symbols: SymbolTable,
+}
+
+impl Binary {
+    pub fn to_bytes(&self) -> Vec<u8> {
+	let data = encode_data(&self.data);
+	let symbols = encode_symbols(&self.symbols);
+	let header = Header {
+	    version: VERSION,
+	    entry: self.entry,
+	    code_len: self.code.len() as u64,
+	    data_len: data.len() as u64,
+	    symbols_len: symbols.len() as u64,
+	};
+	let mut bytes = header.to_bytes();
+	bytes.extend_from_slice(&self.code);
+	bytes.extend_from_slice(&data);
+	bytes.extend_from_slice(&symbols);
+	bytes
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Binary, LoadError> {
+	let header = Header::parse(bytes)?;
+	let code_end = HEADER_LEN + header.code_len as usize;
+	let data_end = code_end + header.data_len as usize;
+	Ok(Binary {
+	    entry: header.entry,
+	    code: bytes[HEADER_LEN..code_end].to_vec(),
+	    data: decode_data(&bytes[code_end..data_end])?,
+	    symbols: decode_symbols(&bytes[data_end..])?,
+	})
+    }
+}
+
+/// Whether `bytes` looks like a bedrock binary rather than assembly source
+pub fn is_binary(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+fn encode_data(blocks: &[Vec<Val>]) -> Vec<u8> {
+    let mut bytes = vec![];
+    bytes.extend_from_slice(&(blocks.len() as u64).to_be_bytes());
+    for block in blocks {
+	bytes.extend_from_slice(&(block.len() as u64).to_be_bytes());
+	for cell in block {
+	    match cell {
+		Val::Int(v) => {
+		    bytes.push(0);
+		    bytes.extend_from_slice(&v.to_be_bytes());
+		},
+		Val::Ptr(v) => {
+		    bytes.push(1);
+		    bytes.extend_from_slice(&v.to_be_bytes());
+		},
+		Val::Float(v) => {
+		    bytes.push(2);
+		    bytes.extend_from_slice(&v.to_bits().to_be_bytes());
+		},
+	    }
+	}
+    }
+    bytes
+}
+
+fn decode_data(bytes: &[u8]) -> Result<Vec<Vec<Val>>, LoadError> {
+    let mut reader = Reader::new(bytes);
+    let count = reader.u64()?;
+    let mut blocks = vec![];
+    for _ in 0..count {
+	let len = reader.u64()?;
+	let mut block = vec![];
+	for _ in 0..len {
+	    let cell = match reader.u8()? {
+		0 => Val::Int(reader.u64()? as i64),
+		1 => Val::Ptr(reader.u64()?),
+		2 => Val::Float(f64::from_bits(reader.u64()?)),
+		tag => return Err(LoadError::Malformed { reason: format!("unknown data cell tag {}", tag) }),
+	    };
+	    block.push(cell);
+	}
+	blocks.push(block);
+    }
+    reader.finish("data section")?;
+    Ok(blocks)
+}
+
+/// Panics if a name is longer than `MAX_SYMBOL_NAME_LEN`; the assembler
+/// rejects those
+fn encode_symbols(symbols: &SymbolTable) -> Vec<u8> {
+    let mut bytes = vec![];
+    bytes.extend_from_slice(&(symbols.symbols().len() as u64).to_be_bytes());
+    for symbol in symbols.symbols() {
+	bytes.push(match symbol.symbol_type {
+	    SymbolType::Label => 0,
+	    SymbolType::Data => 1,
+	    SymbolType::Extern => 2,
+	});
+	let len = u16::try_from(symbol.name.len()).expect("symbol name is longer than MAX_SYMBOL_NAME_LEN");
+	bytes.extend_from_slice(&len.to_be_bytes());
+	bytes.extend_from_slice(symbol.name.as_bytes());
+	bytes.extend_from_slice(&symbol.offset.to_be_bytes());
+    }
+    bytes
+}
+
+fn decode_symbols(bytes: &[u8]) -> Result<SymbolTable, LoadError> {
+    let mut reader = Reader::new(bytes);
+    let count = reader.u64()?;
+    let mut symbols = SymbolTable::new();
+    for _ in 0..count {
+	let symbol_type = match reader.u8()? {
+	    0 => SymbolType::Label,
+	    1 => SymbolType::Data,
+	    2 => SymbolType::Extern,
+	    tag => return Err(LoadError::Malformed { reason: format!("unknown symbol type {}", tag) }),
+	};
+	let len = reader.u16()? as usize;
+	let name = match String::from_utf8(reader.take(len)?.to_vec()) {
+	    Ok(name) => name,
+	    Err(_) => return Err(LoadError::Malformed { reason: "symbol name is not valid UTF-8".to_string() }),
+	};
+	let offset = reader.u64()?;
+	symbols.add_symbol(Symbol::new(name, symbol_type, offset));
+    }
+    reader.finish("symbol table")?;
+    Ok(symbols)
+}
+
+/// A cursor over a section that reports running off the end as `Truncated`
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Reader<'a> {
+	Reader { bytes, pos: 0 }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], LoadError> {
+	if self.bytes.len() - self.pos < n {
+	    return Err(LoadError::Truncated);
+	}
+	let result = &self.bytes[self.pos..self.pos + n];
+	self.pos += n;
+	Ok(result)
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+	Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, LoadError> {
+	let mut buf = [0; 2];
+	buf.copy_from_slice(self.take(2)?);
+	Ok(u16::from_be_bytes(buf))
+    }
+
+    fn u64(&mut self) -> Result<u64, LoadError> {
+	let mut buf = [0; 8];
+	buf.copy_from_slice(self.take(8)?);
+	Ok(u64::from_be_bytes(buf))
+    }
+
+    fn finish(&self, section: &str) -> Result<(), LoadError> {
+	if self.pos != self.bytes.len() {
+	    return Err(LoadError::Malformed { reason: format!("trailing bytes after the {}", section) });
+	}
+	Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Binary {
+	let mut symbols = SymbolTable::new();
+	symbols.add_symbol(Symbol::new("main".to_string(), SymbolType::Label, 1));
+	symbols.add_symbol(Symbol::new("greeting".to_string(), SymbolType::Data, 0));
+	symbols.add_symbol(Symbol::new("println".to_string(), SymbolType::Extern, 0));
+	Binary {
+	    entry: 1,
+	    code: vec![0, 0],
+	    data: vec![vec![Val::Int(-1), Val::Ptr(7), Val::Float(0.25)]],
+	    symbols,
+	}
+    }
+
+    #[test]
+    fn test_round_trip() {
+	let binary = sample();
+	let bytes = binary.to_bytes();
+	assert!(is_binary(&bytes));
+	assert_eq!(&bytes[..4], b"BDRK");
+	assert_eq!(Binary::parse(&bytes), Ok(binary));
+    }
+
+    #[test]
+    fn test_rejects_bad_headers() {
+	let bytes = sample().to_bytes();
+	assert_eq!(Binary::parse(&[0, 1, 2, 3, 4]), Err(LoadError::BadMagic));
+
+	let mut wrong_version = bytes.clone();
+	wrong_version[5] = 9;
+	assert_eq!(Binary::parse(&wrong_version), Err(LoadError::UnsupportedVersion { found: 9, supported: VERSION }));
+
+	assert_eq!(Binary::parse(&bytes[..bytes.len() - 1]), Err(LoadError::Truncated));
+	assert_eq!(Binary::parse(&bytes[..10]), Err(LoadError::Truncated));
+
+	let mut bad_entry = bytes.clone();
+	bad_entry[15] = 3;
+	assert!(Binary::parse(&bad_entry).is_err());
+    }
+}
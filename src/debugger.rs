@@ -0,0 +1,352 @@
+//! Breakpoints, watchpoints and stepping, built on `VM::execute_instruction`.
+//!
+//! A `Debugger` holds no program state of its own beyond what to stop on; it
+//! drives whatever `VM` it is handed. Breakpoints stop execution before the
+//! instruction at their address runs. Watchpoints stop it after any
+//! instruction that changes the register or heap cell they watch.
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::assembler::symbols::SymbolType;
+use crate::disassembler;
+use crate::instruction::Opcode;
+use crate::vm::{Val, VmError, VM};
+
+/// Something a watchpoint can watch
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Watch {
+    Register(u8),
+    Cell { block: u64, offset: u64 },
+}
+
+impl Watch {
+    /// The watched value, or None for a cell that does not exist (yet)
+    fn read(self, vm: &VM) -> Option<Val> {
+	match self {
+	    Watch::Register(r) => Some(vm.registers[r as usize]),
+	    Watch::Cell { block, offset } => vm.heap.get(block).ok().and_then(|k| k.data.get(offset as usize).cloned()),
+	}
+    }
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	match self {
+	    Watch::Register(r) => write!(f, "r{}", r),
+	    Watch::Cell { block, offset } => write!(f, "block {} offset {}", block, offset),
+	}
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Watchpoint {
+    watch: Watch,
+    last: Option<Val>,
+}
+
+/// A watched value that changed, and what it changed from and to
+#[derive(Debug, PartialEq, Clone)]
+pub struct WatchChange {
+    pub watch: Watch,
+    pub old: Option<Val>,
+    pub new: Option<Val>,
+}
+
+impl fmt::Display for WatchChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	write!(f, "{} changed from {} to {}", self.watch, show(&self.old), show(&self.new))
+    }
+}
+
+/// Why the debugger handed control back
+#[derive(Debug, PartialEq, Clone)]
+pub enum StopReason {
+    /// A single step finished
+    Stepped,
+    /// Execution reached a breakpoint at this address
+    Breakpoint(usize),
+    /// Watched values changed, in the order the watchpoints were added
+    Watchpoint(Vec<WatchChange>),
+    /// The program halted or ran off the end
+    Halted,
+    Error(VmError),
+}
+
+fn show(v: &Option<Val>) -> String {
+    match v {
+	Some(v) => format!("{:?}", v),
+	None => "nothing".to_string(),
+    }
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	match self {
+	    StopReason::Stepped => write!(f, "stepped"),
+	    StopReason::Breakpoint(address) => write!(f, "breakpoint at {}", address),
+	    StopReason::Watchpoint(changes) => {
+		let changes: Vec<String> = changes.iter().map(|c| c.to_string()).collect();
+		write!(f, "{}", changes.join(", "))
+	    },
+	    StopReason::Halted => write!(f, "program halted"),
+	    StopReason::Error(e) => write!(f, "error: {}", e),
+	}
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<usize>,
+    watchpoints: Vec<Watchpoint>,
+    halted: bool,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+	Debugger::default()
+    }
+
+    /// Forgets that the program halted, for when a new one is loaded.
+    /// Breakpoints and watchpoints are kept.
+    pub fn reset(&mut self) {
+	self.halted = false;
+    }
+
+    pub fn add_breakpoint(&mut self, address: usize) {
+	self.breakpoints.insert(address);
+    }
+
+    /// Sets a breakpoint on a code label from the VM's symbol table and
+    /// returns its address, or None if there is no such label
+    pub fn add_label_breakpoint(&mut self, vm: &VM, label: &str) -> Option<usize> {
+	let address = match vm.symbols.symbol(label) {
+	    Some(symbol) if symbol.symbol_type == SymbolType::Label => symbol.offset as usize,
+	    _ => return None,
+	};
+	self.add_breakpoint(address);
+	Some(address)
+    }
+
+    pub fn remove_breakpoint(&mut self, address: usize) -> bool {
+	self.breakpoints.remove(&address)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+	self.breakpoints.iter().cloned()
+    }
+
+    /// Starts watching `watch`, remembering its current value
+    pub fn add_watchpoint(&mut self, vm: &VM, watch: Watch) {
+	self.remove_watchpoint(watch);
+	self.watchpoints.push(Watchpoint { watch, last: watch.read(vm) });
+    }
+
+    pub fn remove_watchpoint(&mut self, watch: Watch) -> bool {
+	let before = self.watchpoints.len();
+	self.watchpoints.retain(|w| w.watch != watch);
+	self.watchpoints.len() != before
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = Watch> + '_ {
+	self.watchpoints.iter().map(|w| w.watch)
+    }
+
+    /// Executes one instruction
+    pub fn step(&mut self, vm: &mut VM) -> StopReason {
+	if self.halted {
+	    return StopReason::Halted;
+	}
+	match vm.execute_instruction() {
+	    Ok(true) => {
+		self.halted = true;
+		StopReason::Halted
+	    },
+	    Ok(false) => self.check_watchpoints(vm).unwrap_or(StopReason::Stepped),
+	    Err(e) => StopReason::Error(e),
+	}
+    }
+
+    /// Like `step`, but runs a whole call as one step. Breakpoints and
+    /// watchpoints inside the call still stop it.
+    pub fn next(&mut self, vm: &mut VM) -> StopReason {
+	let is_call = match vm.program.get(vm.pc()) {
+	    Some(b) => matches!(Opcode::from(*b), Opcode::Call | Opcode::CallI),
+	    None => false,
+	};
+	let depth = vm.call_stack.len();
+	let reason = self.step(vm);
+	if !is_call || reason != StopReason::Stepped || vm.call_stack.len() <= depth {
+	    return reason;
+	}
+	self.run_while(vm, |vm| vm.call_stack.len() > depth)
+    }
+
+    /// Runs until a breakpoint, a watchpoint, the end of the program or an
+    /// error. A breakpoint at the current address does not stop it.
+    pub fn cont(&mut self, vm: &mut VM) -> StopReason {
+	match self.step(vm) {
+	    StopReason::Stepped => self.run_while(vm, |_| true),
+	    reason => reason,
+	}
+    }
+
+    /// Keeps stepping while `keep_going` holds, stopping early for anything
+    /// `cont` would stop for
+    fn run_while<F: Fn(&VM) -> bool>(&mut self, vm: &mut VM, keep_going: F) -> StopReason {
+	while keep_going(vm) {
+	    if self.breakpoints.contains(&vm.pc()) {
+		return StopReason::Breakpoint(vm.pc());
+	    }
+	    match self.step(vm) {
+		StopReason::Stepped => {},
+		reason => return reason,
+	    }
+	}
+	StopReason::Stepped
+    }
+
+    fn check_watchpoints(&mut self, vm: &VM) -> Option<StopReason> {
+	let mut changes = vec![];
+	for w in &mut self.watchpoints {
+	    let new = w.watch.read(vm);
+	    let unchanged = match (new, w.last) {
+		(Some(new), Some(last)) => new.identical(&last),
+		(new, last) => new == last,
+	    };
+	    if !unchanged {
+		let old = std::mem::replace(&mut w.last, new);
+		changes.push(WatchChange { watch: w.watch, old, new });
+	    }
+	}
+	if changes.is_empty() {
+	    None
+	} else {
+	    Some(StopReason::Watchpoint(changes))
+	}
+    }
+
+    /// The instruction at the VM's `pc`, disassembled and preceded by its
+    /// address and any labels on it
+    pub fn current_instruction(vm: &VM) -> String {
+	let pc = vm.pc();
+	if pc >= vm.program.len() {
+	    return format!("{:04}: <end of program>", pc);
+	}
+	let labels: Vec<&str> = vm.symbols.symbols().iter()
+	    .filter(|s| s.symbol_type == SymbolType::Label && s.offset == pc as u64)
+	    .map(|s| s.name.as_str())
+	    .collect();
+	let prefix = if labels.is_empty() { String::new() } else { format!("{}: ", labels.join(": ")) };
+	match disassembler::decode(&vm.program, pc) {
+	    Ok(ins) => format!("{:04}: {}{}", pc, prefix, ins),
+	    Err(e) => format!("{:04}: {}{}", pc, prefix, e),
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn load(source: &str) -> VM {
+	let mut asm = Assembler::new();
+	let mut vm = VM::new();
+	vm.program = asm.assemble(source).unwrap();
+	vm.symbols = asm.symbols.clone();
+	vm
+    }
+
+    const PROGRAM: &str = "load r0 1\ncall @double\naddi r0 r0 1\nhlt\ndouble: add r0 r0 r0\nret\n";
+
+    #[test]
+    fn test_breakpoints_and_stepping() {
+	let mut vm = load(PROGRAM);
+	let mut debugger = Debugger::new();
+	assert_eq!(debugger.add_label_breakpoint(&vm, "double"), Some(14));
+	assert_eq!(debugger.add_label_breakpoint(&vm, "nowhere"), None);
+	assert_eq!(Debugger::current_instruction(&vm), "0000: load r0 1");
+	assert_eq!(debugger.cont(&mut vm), StopReason::Breakpoint(14));
+	assert_eq!(Debugger::current_instruction(&vm), "0014: double: add r0 r0 r0");
+	assert_eq!(debugger.step(&mut vm), StopReason::Stepped);
+	assert_eq!(vm.registers[0], Val::Int(2));
+	assert_eq!(debugger.cont(&mut vm), StopReason::Halted);
+	assert_eq!(vm.registers[0], Val::Int(3));
+	assert_eq!(debugger.step(&mut vm), StopReason::Halted);
+    }
+
+    #[test]
+    fn test_next_steps_over_calls() {
+	let mut vm = load(PROGRAM);
+	let mut debugger = Debugger::new();
+	debugger.step(&mut vm);
+	assert_eq!(debugger.next(&mut vm), StopReason::Stepped);
+	assert_eq!(vm.pc(), 9);
+	assert_eq!(vm.registers[0], Val::Int(2));
+	// A breakpoint inside the call still stops `next`
+	let mut vm = load(PROGRAM);
+	debugger.reset();
+	debugger.add_breakpoint(18);
+	debugger.step(&mut vm);
+	assert_eq!(debugger.next(&mut vm), StopReason::Breakpoint(18));
+    }
+
+    #[test]
+    fn test_watchpoints() {
+	let mut vm = load("alloc r1 r2\nload r3 0\nload r4 9\nwrite r1 r3 5\nload r0 1\nload r0 1\nload r0 2\nhlt\n");
+	vm.registers[2] = Val::Int(1);
+	let mut debugger = Debugger::new();
+	debugger.add_watchpoint(&vm, Watch::Register(0));
+	debugger.add_watchpoint(&vm, Watch::Cell { block: 0, offset: 0 });
+	let reason = debugger.cont(&mut vm);
+	assert_eq!(reason, StopReason::Watchpoint(vec![WatchChange { watch: Watch::Cell { block: 0, offset: 0 }, old: None, new: Some(Val::Int(0)) }]));
+	assert_eq!(reason.to_string(), "block 0 offset 0 changed from nothing to Int(0)");
+	let reason = debugger.cont(&mut vm);
+	assert_eq!(reason, StopReason::Watchpoint(vec![WatchChange { watch: Watch::Cell { block: 0, offset: 0 }, old: Some(Val::Int(0)), new: Some(Val::Int(5)) }]));
+	let reason = debugger.cont(&mut vm);
+	assert_eq!(reason, StopReason::Watchpoint(vec![WatchChange { watch: Watch::Register(0), old: Some(Val::Int(0)), new: Some(Val::Int(1)) }]));
+	let reason = debugger.cont(&mut vm);
+	assert_eq!(reason, StopReason::Watchpoint(vec![WatchChange { watch: Watch::Register(0), old: Some(Val::Int(1)), new: Some(Val::Int(2)) }]));
+	assert!(debugger.remove_watchpoint(Watch::Register(0)));
+	assert_eq!(debugger.cont(&mut vm), StopReason::Halted);
+    }
+
+    #[test]
+    fn test_watchpoints_report_every_change() {
+	let mut vm = load("alloc r1 r2\nhlt\n");
+	vm.registers[2] = Val::Int(1);
+	let mut debugger = Debugger::new();
+	debugger.add_watchpoint(&vm, Watch::Register(1));
+	debugger.add_watchpoint(&vm, Watch::Cell { block: 0, offset: 0 });
+	let reason = debugger.step(&mut vm);
+	assert_eq!(reason, StopReason::Watchpoint(vec![
+	    WatchChange { watch: Watch::Register(1), old: Some(Val::Int(0)), new: Some(Val::Ptr(0)) },
+	    WatchChange { watch: Watch::Cell { block: 0, offset: 0 }, old: None, new: Some(Val::Int(0)) },
+	]));
+	assert_eq!(reason.to_string(), "r1 changed from Int(0) to Ptr(0), block 0 offset 0 changed from nothing to Int(0)");
+    }
+
+    #[test]
+    fn test_watchpoints_compare_float_bits() {
+	let mut vm = load("load r1 1\nload r1 2\nhlt\n");
+	vm.registers[0] = Val::Float(f64::NAN);
+	vm.registers[2] = Val::Float(0.0);
+	let mut debugger = Debugger::new();
+	debugger.add_watchpoint(&vm, Watch::Register(0));
+	debugger.add_watchpoint(&vm, Watch::Register(2));
+	assert_eq!(debugger.step(&mut vm), StopReason::Stepped);
+	vm.registers[2] = Val::Float(-0.0);
+	let reason = debugger.cont(&mut vm);
+	assert_eq!(reason, StopReason::Watchpoint(vec![WatchChange { watch: Watch::Register(2), old: Some(Val::Float(0.0)), new: Some(Val::Float(-0.0)) }]));
+	assert_eq!(debugger.cont(&mut vm), StopReason::Halted);
+    }
+
+    #[test]
+    fn test_stops_on_errors() {
+	let mut vm = load("load r0 1\ndiv r0 r1 r2\nhlt\n");
+	let mut debugger = Debugger::new();
+	assert_eq!(debugger.cont(&mut vm), StopReason::Error(VmError::DivisionByZero { pc: 3 }));
+	assert_eq!(vm.pc(), 3);
+    }
+}
@@ -0,0 +1,166 @@
+//! Bytecode decoded ahead of time.
+//!
+//! `VM::run` decodes each instruction from `program` as it reaches it.
+//! `VM::run_decoded` instead decodes the whole program once into a
+//! `DecodedProgram`, and immediate jump targets are resolved to instruction
+//! indices so a taken jump does not have to look its target up. Addresses
+//! stay byte offsets everywhere the program can see them.
+
+use crate::encoding::{read_float, read_sleb, read_uleb, DecodeError};
+use crate::instruction::{Opcode, OperandKind};
+
+/// One instruction with its operands already read out of the bytecode
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct DecodedInstruction {
+    pub opcode: Opcode,
+    /// Address of the opcode byte
+    pub address: usize,
+    /// Address of the instruction that follows
+    pub next: usize,
+    /// Register operands in the order they appear
+    pub regs: [u8; 3],
+    /// Bits of the immediate operand, if there is one; read it with `int`,
+    /// `uint` or `float` according to the opcode's operand kinds
+    pub imm: u64,
+    /// For immediate jumps and `CallI`, the index of the instruction at the
+    /// target, if the target starts one
+    pub target: Option<usize>,
+}
+
+impl DecodedInstruction {
+    pub fn int(&self) -> i64 {
+	self.imm as i64
+    }
+
+    pub fn uint(&self) -> u64 {
+	self.imm
+    }
+
+    pub fn float(&self) -> f64 {
+	f64::from_bits(self.imm)
+    }
+}
+
+/// Decodes the instruction starting at `bytes[address]`
+pub fn decode_instruction(bytes: &[u8], address: usize) -> Result<DecodedInstruction, DecodeError> {
+    let opcode = match bytes.get(address) {
+	Some(b) => Opcode::from(*b),
+	None => return Err(DecodeError::Truncated),
+    };
+    let mut ins = DecodedInstruction {
+	opcode,
+	address,
+	next: address + 1,
+	regs: [0; 3],
+	imm: 0,
+	target: None,
+    };
+    let mut regs = 0;
+    for kind in opcode.operands() {
+	let (value, len) = match kind {
+	    OperandKind::Register => match bytes.get(ins.next) {
+		Some(r) => {
+		    ins.regs[regs] = *r;
+		    regs += 1;
+		    ins.next += 1;
+		    continue;
+		},
+		None => return Err(DecodeError::Truncated),
+	    },
+	    OperandKind::Int => read_sleb(bytes, ins.next).map(|(v, len)| (v as u64, len))?,
+	    OperandKind::UInt => read_uleb(bytes, ins.next)?,
+	    OperandKind::Float => read_float(bytes, ins.next).map(|(v, len)| (v.to_bits(), len))?,
+	};
+	ins.imm = value;
+	ins.next += len;
+    }
+    Ok(ins)
+}
+
+/// A whole program decoded front to back
+#[derive(Debug, Default)]
+pub struct DecodedProgram {
+    pub instructions: Vec<DecodedInstruction>,
+    /// Instruction index for each byte address that starts an instruction
+    index: Vec<Option<usize>>,
+}
+
+impl DecodedProgram {
+    /// Decodes `bytes` up to the end or the first instruction that cannot be
+    /// decoded. Whatever is left is for the VM to report when it gets there.
+    pub fn new(bytes: &[u8]) -> DecodedProgram {
+	let mut program = DecodedProgram {
+	    instructions: vec![],
+	    index: vec![None; bytes.len()],
+	};
+	let mut address = 0;
+	while let Ok(ins) = decode_instruction(bytes, address) {
+	    program.index[address] = Some(program.instructions.len());
+	    program.instructions.push(ins);
+	    address = ins.next;
+	}
+	for i in 0..program.instructions.len() {
+	    let ins = program.instructions[i];
+	    if ins.opcode.has_immediate_target() {
+		program.instructions[i].target = program.index_of(ins.uint() as usize);
+	    }
+	}
+	program
+    }
+
+    /// Index of the instruction starting at `address`, if one does
+    pub fn index_of(&self, address: usize) -> Option<usize> {
+	self.index.get(address).cloned().flatten()
+    }
+
+    /// Index of the instruction to run once instruction `i` has left the VM's
+    /// `pc` at `address`
+    pub fn successor(&self, i: usize, address: usize) -> Option<usize> {
+	let ins = &self.instructions[i];
+	if address == ins.next {
+	    return if i + 1 < self.instructions.len() { Some(i + 1) } else { None };
+	}
+	match ins.target {
+	    Some(t) if self.instructions[t].address == address => Some(t),
+	    _ => self.index_of(address),
+	}
+    }
+
+    pub fn len(&self) -> usize {
+	self.instructions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+	self.instructions.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_instruction() {
+	let ins = decode_instruction(&[49, 3, 1, 0x0d], 0).unwrap();
+	assert_eq!(ins.opcode, Opcode::SubI);
+	assert_eq!(ins.regs, [3, 1, 0]);
+	assert_eq!(ins.int(), -7);
+	assert_eq!(ins.next, 4);
+	assert_eq!(decode_instruction(&[1, 0], 0), Err(DecodeError::Truncated));
+    }
+
+    #[test]
+    fn test_jump_targets() {
+	// load r0 5; jmpi 0; hlt
+	let program = DecodedProgram::new(&[1, 0, 10, 51, 0, 0]);
+	assert_eq!(program.len(), 3);
+	assert_eq!(program.index_of(3), Some(1));
+	assert_eq!(program.index_of(4), None);
+	assert_eq!(program.instructions[1].target, Some(0));
+	assert_eq!(program.successor(1, 0), Some(0));
+	assert_eq!(program.successor(0, 3), Some(1));
+	assert_eq!(program.successor(2, 6), None);
+	// Decoding stops at the truncated load
+	assert_eq!(DecodedProgram::new(&[0, 1, 0]).len(), 1);
+    }
+}
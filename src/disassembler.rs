@@ -0,0 +1,187 @@
+use std::error;
+use std::fmt;
+
+use crate::assembler::symbols::{SymbolTable, SymbolType};
+use crate::encoding::{read_float, read_sleb, read_uleb, DecodeError};
+use crate::instruction::{Opcode, OperandKind};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Operand {
+    Register(u8),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	match self {
+	    Operand::Register(r) => write!(f, "r{}", r),
+	    Operand::Int(v) => write!(f, "{}", v),
+	    Operand::UInt(v) => write!(f, "{}", v),
+	    // `{:?}` always keeps a decimal point or exponent, so it reads back as a float
+	    Operand::Float(v) => write!(f, "{:?}", v),
+	}
+    }
+}
+
+/// One decoded instruction. Its `Display` output is valid assembly.
+#[derive(Debug, PartialEq, Clone)]
+pub struct DisassembledInstruction {
+    pub address: usize,
+    pub opcode: Opcode,
+    /// The raw opcode byte, which only differs from `opcode` for `Igl`
+    pub byte: u8,
+    pub operands: Vec<Operand>,
+    /// Number of bytes the instruction occupies, opcode included
+    pub len: usize,
+}
+
+impl fmt::Display for DisassembledInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	write!(f, "{}", self.opcode.mnemonic())?;
+	for operand in &self.operands {
+	    write!(f, " {}", operand)?;
+	}
+	Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum DisassemblerError {
+    TruncatedOperand { address: usize },
+    MalformedOperand { address: usize },
+    /// There is no code at the address
+    PastEnd { address: usize },
+}
+
+impl fmt::Display for DisassemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	match self {
+	    DisassemblerError::TruncatedOperand { address } => write!(f, "instruction at {} is missing operand bytes", address),
+	    DisassemblerError::MalformedOperand { address } => write!(f, "instruction at {} has an overlong immediate", address),
+	    DisassemblerError::PastEnd { address } => write!(f, "address {} is past the end of the code", address),
+	}
+    }
+}
+
+impl error::Error for DisassemblerError {}
+
+/// Decodes the instruction starting at `address`, reading operands the same
+/// way the VM does. Unknown opcode bytes decode as a one byte `Igl`.
+pub fn decode(bytes: &[u8], address: usize) -> Result<DisassembledInstruction, DisassemblerError> {
+    let byte = match bytes.get(address) {
+	Some(b) => *b,
+	None => return Err(DisassemblerError::PastEnd { address }),
+    };
+    let opcode = Opcode::from(byte);
+    let mut pc = address + 1;
+    let mut operands = vec![];
+    for kind in opcode.operands() {
+	let decoded = match kind {
+	    OperandKind::Register => match bytes.get(pc) {
+		Some(r) => Ok((Operand::Register(*r), 1)),
+		None => Err(DecodeError::Truncated),
+	    },
+	    OperandKind::Int => read_sleb(bytes, pc).map(|(v, len)| (Operand::Int(v), len)),
+	    OperandKind::UInt => read_uleb(bytes, pc).map(|(v, len)| (Operand::UInt(v), len)),
+	    OperandKind::Float => read_float(bytes, pc).map(|(v, len)| (Operand::Float(v), len)),
+	};
+	let (operand, len) = match decoded {
+	    Ok(decoded) => decoded,
+	    Err(DecodeError::Truncated) => return Err(DisassemblerError::TruncatedOperand { address }),
+	    Err(DecodeError::Overlong) => return Err(DisassemblerError::MalformedOperand { address }),
+	};
+	operands.push(operand);
+	pc += len;
+    }
+    Ok(DisassembledInstruction {
+	address,
+	opcode,
+	byte,
+	operands,
+	len: pc - address,
+    })
+}
+
+/// Decodes every instruction in `bytes`
+pub fn disassemble(bytes: &[u8]) -> Result<Vec<DisassembledInstruction>, DisassemblerError> {
+    let mut pc = 0;
+    let mut results = vec![];
+    while pc < bytes.len() {
+	let instruction = decode(bytes, pc)?;
+	pc += instruction.len;
+	results.push(instruction);
+    }
+    Ok(results)
+}
+
+/// Renders `bytes` as one instruction per line, each prefixed with its
+/// address. Code labels from `symbols` are put back in front of the
+/// instructions they point at.
+pub fn listing(bytes: &[u8], symbols: &SymbolTable) -> Result<String, DisassemblerError> {
+    let mut out = String::new();
+    for instruction in disassemble(bytes)? {
+	let labels = symbols.symbols().iter().filter(|s| {
+	    s.symbol_type == SymbolType::Label && s.offset == instruction.address as u64
+	});
+	for label in labels {
+	    out.push_str(&format!("{}:\n", label.name));
+	}
+	if instruction.opcode == Opcode::Igl {
+	    out.push_str(&format!("{:04}: igl (byte {})\n", instruction.address, instruction.byte));
+	} else {
+	    out.push_str(&format!("{:04}: {}\n", instruction.address, instruction));
+	}
+    }
+    Ok(out)
+}
+
+/// Renders `bytes` as assembly source that reassembles to the same program
+pub fn source(bytes: &[u8]) -> Result<String, DisassemblerError> {
+    let mut out = String::new();
+    for instruction in disassemble(bytes)? {
+	out.push_str(&format!("{}\n", instruction));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    #[test]
+    fn test_decode_instruction() {
+	let bytes = vec![1, 0, 231, 7];
+	let instruction = decode(&bytes, 0).unwrap();
+	assert_eq!(instruction.opcode, Opcode::Load);
+	assert_eq!(instruction.operands, vec![Operand::Register(0), Operand::Int(-500)]);
+	assert_eq!(instruction.len, 4);
+	assert_eq!(instruction.to_string(), "load r0 -500");
+    }
+
+    #[test]
+    fn test_truncated_instruction() {
+	assert_eq!(disassemble(&[1, 0, 0x80]), Err(DisassemblerError::TruncatedOperand { address: 0 }));
+	assert_eq!(decode(&[0], 1), Err(DisassemblerError::PastEnd { address: 1 }));
+    }
+
+    #[test]
+    fn test_round_trip() {
+	let source_text = "load r0 100\nload r1 -2\nadd r0 r1 r2\nloadptr r3 7\nwriteptr r3 r1 9\nloadf r4 -2500.0\nloadf r5 3.14\ncall r0\nret\nhlt\n";
+	let mut asm = Assembler::new();
+	let bytes = asm.assemble(source_text).unwrap();
+	let text = source(&bytes).unwrap();
+	assert_eq!(text, source_text);
+	assert_eq!(Assembler::new().assemble(&text).unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_listing_with_labels() {
+	let mut asm = Assembler::new();
+	let bytes = asm.assemble("load r0 1\nend: hlt\n").unwrap();
+	let text = listing(&bytes, &asm.symbols).unwrap();
+	assert_eq!(text, "0000: load r0 1\nend:\n0003: hlt\n");
+    }
+}
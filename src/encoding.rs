@@ -0,0 +1,161 @@
+//! How immediates are laid out in bytecode.
+//!
+//! Unsigned immediates are LEB128: seven bits per byte, least significant
+//! group first, with the high bit set on every byte but the last. Signed
+//! immediates are zigzag encoded first (0, -1, 1, -2, ... become 0, 1, 2, 3,
+//! ...) so that small negative numbers stay short too. Floats are always the
+//! 8 byte big endian IEEE 754 bit pattern.
+
+/// The most bytes a LEB128 encoded `u64` can take
+pub const MAX_LEB_LEN: usize = 10;
+
+/// Width that label references are padded to, so their size is known before
+/// the label's address is
+pub const LABEL_LEN: usize = 5;
+
+/// Why an immediate could not be decoded
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum DecodeError {
+    /// The bytes ran out in the middle of the immediate
+    Truncated,
+    /// The immediate is longer than any `u64` needs
+    Overlong,
+}
+
+pub fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+pub fn unzigzag(v: u64) -> i64 {
+    ((v >> 1) as i64) ^ -((v & 1) as i64)
+}
+
+/// Number of bytes `write_uleb` uses for `v`
+pub fn uleb_len(v: u64) -> usize {
+    let bits = 64 - v.leading_zeros() as usize;
+    std::cmp::max(1, bits.div_ceil(7))
+}
+
+pub fn sleb_len(v: i64) -> usize {
+    uleb_len(zigzag(v))
+}
+
+pub fn write_uleb(mut v: u64, out: &mut Vec<u8>) {
+    loop {
+	let byte = (v & 0x7f) as u8;
+	v >>= 7;
+	if v == 0 {
+	    out.push(byte);
+	    return;
+	}
+	out.push(byte | 0x80);
+    }
+}
+
+pub fn write_sleb(v: i64, out: &mut Vec<u8>) {
+    write_uleb(zigzag(v), out);
+}
+
+/// Writes `v` using exactly `width` bytes by padding with empty groups.
+/// Returns false, writing nothing, if `v` does not fit.
+pub fn write_uleb_padded(mut v: u64, width: usize, out: &mut Vec<u8>) -> bool {
+    if uleb_len(v) > width {
+	return false;
+    }
+    for i in 0..width {
+	let byte = (v & 0x7f) as u8;
+	v >>= 7;
+	out.push(if i + 1 < width { byte | 0x80 } else { byte });
+    }
+    true
+}
+
+pub fn write_float(v: f64, out: &mut Vec<u8>) {
+    out.extend_from_slice(&v.to_bits().to_be_bytes());
+}
+
+/// Reads an unsigned immediate starting at `bytes[pos]`, returning it and the
+/// number of bytes it took
+pub fn read_uleb(bytes: &[u8], pos: usize) -> Result<(u64, usize), DecodeError> {
+    let mut result: u64 = 0;
+    for i in 0..MAX_LEB_LEN {
+	let byte = match bytes.get(pos + i) {
+	    Some(b) => *b,
+	    None => return Err(DecodeError::Truncated),
+	};
+	let group = u64::from(byte & 0x7f);
+	// The tenth byte only has room for the top bit of a u64
+	if i == MAX_LEB_LEN - 1 && group > 1 {
+	    return Err(DecodeError::Overlong);
+	}
+	result |= group << (7 * i);
+	if byte & 0x80 == 0 {
+	    return Ok((result, i + 1));
+	}
+    }
+    Err(DecodeError::Overlong)
+}
+
+pub fn read_sleb(bytes: &[u8], pos: usize) -> Result<(i64, usize), DecodeError> {
+    let (v, len) = read_uleb(bytes, pos)?;
+    Ok((unzigzag(v), len))
+}
+
+pub fn read_float(bytes: &[u8], pos: usize) -> Result<(f64, usize), DecodeError> {
+    match bytes.get(pos..pos + 8) {
+	Some(b) => {
+	    let bits = b.iter().fold(0, |acc, b| (acc << 8) | u64::from(*b));
+	    Ok((f64::from_bits(bits), 8))
+	},
+	None => Err(DecodeError::Truncated),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uleb_round_trip() {
+	for &v in &[0, 1, 127, 128, 300, 1 << 35, u64::MAX] {
+	    let mut out = vec![];
+	    write_uleb(v, &mut out);
+	    assert_eq!(out.len(), uleb_len(v));
+	    assert_eq!(read_uleb(&out, 0), Ok((v, out.len())));
+	}
+	let mut out = vec![];
+	write_uleb(300, &mut out);
+	assert_eq!(out, vec![0xac, 0x02]);
+    }
+
+    #[test]
+    fn test_sleb_round_trip() {
+	assert_eq!(zigzag(0), 0);
+	assert_eq!(zigzag(-1), 1);
+	assert_eq!(zigzag(1), 2);
+	for &v in &[0, -1, 63, -64, 64, i64::MAX, i64::MIN] {
+	    let mut out = vec![];
+	    write_sleb(v, &mut out);
+	    assert_eq!(out.len(), sleb_len(v));
+	    assert_eq!(read_sleb(&out, 0), Ok((v, out.len())));
+	}
+    }
+
+    #[test]
+    fn test_padded() {
+	let mut out = vec![];
+	assert!(write_uleb_padded(5, LABEL_LEN, &mut out));
+	assert_eq!(out, vec![0x85, 0x80, 0x80, 0x80, 0x00]);
+	assert_eq!(read_uleb(&out, 0), Ok((5, LABEL_LEN)));
+	assert!(!write_uleb_padded(1 << 35, LABEL_LEN, &mut out));
+	assert_eq!(out.len(), LABEL_LEN);
+    }
+
+    #[test]
+    fn test_malformed() {
+	assert_eq!(read_uleb(&[0x80, 0x80], 0), Err(DecodeError::Truncated));
+	assert_eq!(read_uleb(&[0xff; 11], 0), Err(DecodeError::Overlong));
+	assert_eq!(read_uleb(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02], 0), Err(DecodeError::Overlong));
+	assert_eq!(read_float(&[0; 7], 0), Err(DecodeError::Truncated));
+    }
+}
@@ -0,0 +1,393 @@
+use std::collections::hash_map;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::vm::{MemBlock, Val};
+
+/// Why a heap operation was refused
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum HeapError {
+    /// The id was never handed out by this heap
+    UnknownBlock,
+    /// The block was freed and must not be used again
+    UseAfterFree,
+    /// The block was already freed
+    DoubleFree,
+}
+
+/// Why `Heap::try_alloc` refused a block. Each carries the limit in question.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum AllocError {
+    /// The block would be bigger than `max_block_size`
+    BlockTooLarge { max: usize },
+    /// There are already `max_blocks` live blocks
+    TooManyBlocks { max: usize },
+    /// The heap would hold more than `max_cells` cells
+    OutOfCells { max: usize },
+    /// The host could not provide the memory
+    OutOfMemory,
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	match self {
+	    AllocError::BlockTooLarge { max } => write!(f, "blocks are limited to {} cells", max),
+	    AllocError::TooManyBlocks { max } => write!(f, "the heap is limited to {} blocks", max),
+	    AllocError::OutOfCells { max } => write!(f, "the heap is limited to {} cells", max),
+	    AllocError::OutOfMemory => write!(f, "out of memory"),
+	}
+    }
+}
+
+/// Number of live blocks at which the first collection is triggered
+pub const DEFAULT_GC_THRESHOLD: usize = 1024;
+
+/// Largest block `try_alloc` hands out unless told otherwise: 16M cells, a
+/// few hundred megabytes
+pub const DEFAULT_MAX_BLOCK_SIZE: usize = 1 << 24;
+
+/// Counters kept by the garbage collector
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+pub struct GcStats {
+    /// Blocks currently allocated
+    pub live: usize,
+    /// Blocks reclaimed by all collections so far
+    pub freed: u64,
+    /// Number of collections run
+    pub collections: u64,
+}
+
+impl fmt::Display for GcStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	write!(f, "live blocks: {}, freed: {}, collections: {}", self.live, self.freed, self.collections)
+    }
+}
+
+/// The VM's heap. Every block is created by `alloc`, and ids are handed out in
+/// increasing order and never reused, so any id below `next_id` that has no
+/// block must have been freed.
+///
+/// Unreachable blocks are reclaimed by a mark-and-sweep collector. Blocks
+/// below `pinned` came from the data section and are always treated as roots,
+/// since programs refer to them by plain integer ids.
+///
+/// `try_alloc` enforces the `max_` limits. Only `max_block_size` is set by
+/// default, to `DEFAULT_MAX_BLOCK_SIZE`; `alloc` is for the host and ignores
+/// them.
+#[derive(Debug)]
+pub struct Heap {
+    blocks: HashMap<u64, MemBlock>,
+    next_id: u64,
+    pinned: u64,
+    /// Live block count below which no collection runs
+    pub gc_threshold: usize,
+    /// Blocks left alive by the last collection
+    live_after_gc: usize,
+    stats: GcStats,
+    /// Largest block, in cells
+    pub max_block_size: usize,
+    /// Most blocks live at once
+    pub max_blocks: usize,
+    /// Most cells in all live blocks together
+    pub max_cells: usize,
+    /// Cells in all live blocks together
+    cells: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+	Heap::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+	Heap {
+	    blocks: HashMap::new(),
+	    next_id: 0,
+	    pinned: 0,
+	    gc_threshold: DEFAULT_GC_THRESHOLD,
+	    live_after_gc: 0,
+	    stats: GcStats::default(),
+	    max_block_size: DEFAULT_MAX_BLOCK_SIZE,
+	    max_blocks: usize::MAX,
+	    max_cells: usize::MAX,
+	    cells: 0,
+	}
+    }
+
+    /// Allocates a block of `size` cells, all `Val::Int(0)`, and returns its id
+    pub fn alloc(&mut self, size: usize) -> u64 {
+	self.alloc_with(vec![Val::Int(0); size])
+    }
+
+    /// Allocates a block holding `cells` and returns its id
+    pub fn alloc_with(&mut self, cells: Vec<Val>) -> u64 {
+	let id = self.next_id;
+	self.next_id += 1;
+	self.cells += cells.len();
+	self.blocks.insert(id, MemBlock {
+	    length: cells.len() as u64,
+	    data: cells,
+	});
+	id
+    }
+
+    /// `alloc`, unless the block would break one of the heap's limits or the
+    /// memory cannot be had
+    pub fn try_alloc(&mut self, size: usize) -> Result<u64, AllocError> {
+	self.check_alloc(size)?;
+	let mut cells = Vec::new();
+	if cells.try_reserve_exact(size).is_err() {
+	    return Err(AllocError::OutOfMemory);
+	}
+	cells.resize(size, Val::Int(0));
+	Ok(self.alloc_with(cells))
+    }
+
+    /// `alloc_with`, unless the block would break one of the heap's limits
+    pub fn try_alloc_with(&mut self, cells: Vec<Val>) -> Result<u64, AllocError> {
+	self.check_alloc(cells.len())?;
+	Ok(self.alloc_with(cells))
+    }
+
+    /// Readies the heap for a program's block of `size` cells, collecting
+    /// with the pointers in `roots` when a collection is due or when garbage
+    /// may be all that stands in the way of the limits. Whatever allocates on
+    /// a program's behalf calls this before `try_alloc`.
+    pub fn make_room(&mut self, size: usize, roots: &[Val]) -> Result<(), AllocError> {
+	if self.needs_collection() {
+	    self.collect(pointers(roots));
+	}
+	match self.check_alloc(size) {
+	    Err(AllocError::TooManyBlocks { .. }) | Err(AllocError::OutOfCells { .. }) => {
+		self.collect(pointers(roots));
+		self.check_alloc(size)
+	    },
+	    result => result,
+	}
+    }
+
+    fn check_alloc(&self, size: usize) -> Result<(), AllocError> {
+	if size > self.max_block_size {
+	    return Err(AllocError::BlockTooLarge { max: self.max_block_size });
+	}
+	if self.blocks.len() >= self.max_blocks {
+	    return Err(AllocError::TooManyBlocks { max: self.max_blocks });
+	}
+	if size > self.max_cells.saturating_sub(self.cells) {
+	    return Err(AllocError::OutOfCells { max: self.max_cells });
+	}
+	Ok(())
+    }
+
+    pub fn free(&mut self, id: u64) -> Result<(), HeapError> {
+	match self.blocks.remove(&id) {
+	    Some(block) => {
+		self.cells = self.cells.saturating_sub(block.data.len());
+		Ok(())
+	    },
+	    None if id < self.next_id => Err(HeapError::DoubleFree),
+	    None => Err(HeapError::UnknownBlock),
+	}
+    }
+
+    pub fn get(&self, id: u64) -> Result<&MemBlock, HeapError> {
+	match self.blocks.get(&id) {
+	    Some(block) => Ok(block),
+	    None => Err(self.missing(id)),
+	}
+    }
+
+    pub fn get_mut(&mut self, id: u64) -> Result<&mut MemBlock, HeapError> {
+	let error = self.missing(id);
+	self.blocks.get_mut(&id).ok_or(error)
+    }
+
+    /// Number of cells in all live blocks
+    pub fn cells(&self) -> usize {
+	self.cells
+    }
+
+    /// Number of live blocks
+    pub fn len(&self) -> usize {
+	self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+	self.blocks.is_empty()
+    }
+
+    pub fn iter(&self) -> hash_map::Iter<'_, u64, MemBlock> {
+	self.blocks.iter()
+    }
+
+    /// Drops every block and starts handing out ids from 0 again
+    pub fn clear(&mut self) {
+	self.blocks.clear();
+	self.cells = 0;
+	self.next_id = 0;
+	self.pinned = 0;
+	self.live_after_gc = 0;
+	self.stats = GcStats::default();
+    }
+
+    /// Keeps every block allocated so far alive across collections
+    pub fn pin_all(&mut self) {
+	self.pinned = self.next_id;
+    }
+
+    /// Whether the heap has grown enough that a collection should run: it
+    /// holds at least `gc_threshold` blocks and twice as many as the last
+    /// collection left
+    pub fn needs_collection(&self) -> bool {
+	self.blocks.len() >= std::cmp::max(self.gc_threshold, self.live_after_gc * 2)
+    }
+
+    /// Frees every block not reachable from `roots` or a pinned block by
+    /// following `Val::Ptr` cells, and returns how many were freed. The next
+    /// collection is due once the live count doubles.
+    pub fn collect<I: IntoIterator<Item = u64>>(&mut self, roots: I) -> usize {
+	let mut marked = HashSet::new();
+	let mut pending: Vec<u64> = roots.into_iter().chain(0..self.pinned).collect();
+	while let Some(id) = pending.pop() {
+	    if !marked.insert(id) {
+		continue;
+	    }
+	    if let Some(block) = self.blocks.get(&id) {
+		for cell in &block.data {
+		    if let Val::Ptr(p) = *cell {
+			pending.push(p);
+		    }
+		}
+	    }
+	}
+	let before = self.blocks.len();
+	self.blocks.retain(|id, _| marked.contains(id));
+	self.cells = self.blocks.values().map(|block| block.data.len()).sum();
+	let freed = before - self.blocks.len();
+	self.stats.freed += freed as u64;
+	self.stats.collections += 1;
+	self.live_after_gc = self.blocks.len();
+	freed
+    }
+
+    pub fn stats(&self) -> GcStats {
+	GcStats {
+	    live: self.blocks.len(),
+	    ..self.stats
+	}
+    }
+
+    fn missing(&self, id: u64) -> HeapError {
+	if id < self.next_id {
+	    HeapError::UseAfterFree
+	} else {
+	    HeapError::UnknownBlock
+	}
+    }
+}
+
+/// The blocks `vals` point at
+fn pointers(vals: &[Val]) -> impl Iterator<Item = u64> + '_ {
+    vals.iter().filter_map(|v| match *v {
+	Val::Ptr(p) => Some(p),
+	_ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alloc_unique_ids() {
+	let mut heap = Heap::new();
+	let a = heap.alloc(2);
+	let b = heap.alloc(0);
+	assert_ne!(a, b);
+	assert_eq!(heap.get(a).unwrap().data, vec![Val::Int(0), Val::Int(0)]);
+	heap.free(a).unwrap();
+	let c = heap.alloc(1);
+	assert_ne!(a, c);
+	assert_eq!(heap.len(), 2);
+    }
+
+    #[test]
+    fn test_free_errors() {
+	let mut heap = Heap::new();
+	let a = heap.alloc(1);
+	assert_eq!(heap.free(a + 1), Err(HeapError::UnknownBlock));
+	heap.free(a).unwrap();
+	assert_eq!(heap.free(a), Err(HeapError::DoubleFree));
+	assert_eq!(heap.get(a).err(), Some(HeapError::UseAfterFree));
+	assert_eq!(heap.get(a + 1).err(), Some(HeapError::UnknownBlock));
+    }
+
+    #[test]
+    fn test_collect() {
+	let mut heap = Heap::new();
+	let data = heap.alloc(0);
+	heap.pin_all();
+	let root = heap.alloc(1);
+	let child = heap.alloc(0);
+	let garbage = heap.alloc(1);
+	heap.get_mut(root).unwrap().data[0] = Val::Ptr(child);
+	heap.get_mut(garbage).unwrap().data[0] = Val::Ptr(root);
+	assert_eq!(heap.collect(vec![root]), 1);
+	assert!(heap.get(data).is_ok());
+	assert!(heap.get(child).is_ok());
+	assert_eq!(heap.get(garbage).err(), Some(HeapError::UseAfterFree));
+	assert_eq!(heap.collect(vec![]), 2);
+	assert_eq!(heap.stats(), GcStats { live: 1, freed: 3, collections: 2 });
+    }
+
+    #[test]
+    fn test_needs_collection() {
+	let mut heap = Heap::new();
+	heap.gc_threshold = 2;
+	heap.alloc(0);
+	assert!(!heap.needs_collection());
+	let b = heap.alloc(0);
+	assert!(heap.needs_collection());
+	heap.collect(vec![b]);
+	assert!(!heap.needs_collection());
+	heap.alloc(0);
+	assert!(heap.needs_collection());
+    }
+
+    #[test]
+    fn test_collect_cycle() {
+	let mut heap = Heap::new();
+	let a = heap.alloc(1);
+	let b = heap.alloc(1);
+	heap.get_mut(a).unwrap().data[0] = Val::Ptr(b);
+	heap.get_mut(b).unwrap().data[0] = Val::Ptr(a);
+	assert_eq!(heap.collect(vec![a]), 0);
+	assert_eq!(heap.collect(vec![]), 2);
+	assert!(heap.is_empty());
+    }
+
+    #[test]
+    fn test_limits() {
+	let mut heap = Heap::new();
+	assert_eq!(heap.try_alloc(DEFAULT_MAX_BLOCK_SIZE + 1), Err(AllocError::BlockTooLarge { max: DEFAULT_MAX_BLOCK_SIZE }));
+	heap.max_block_size = 4;
+	heap.max_blocks = 3;
+	heap.max_cells = 6;
+	assert_eq!(heap.try_alloc(5), Err(AllocError::BlockTooLarge { max: 4 }));
+	let a = heap.try_alloc(4).unwrap();
+	assert_eq!(heap.try_alloc(3), Err(AllocError::OutOfCells { max: 6 }));
+	heap.try_alloc_with(vec![Val::Int(1); 2]).unwrap();
+	heap.try_alloc(0).unwrap();
+	assert_eq!(heap.try_alloc(0), Err(AllocError::TooManyBlocks { max: 3 }));
+	assert_eq!(heap.cells(), 6);
+	heap.free(a).unwrap();
+	assert_eq!(heap.cells(), 2);
+	heap.try_alloc(4).unwrap();
+	// The host is not held to them
+	heap.alloc(100);
+	assert_eq!(heap.collect(vec![]), 4);
+	assert_eq!(heap.cells(), 0);
+    }
+}
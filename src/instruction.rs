@@ -22,6 +22,54 @@ pub enum Opcode {
     WritePtr,
     Loadptr,
     Deref,
+    Call,
+    Ret,
+    Alloc,
+    Free,
+    LoadF,
+    AddF,
+    SubF,
+    MulF,
+    DivF,
+    Itof,
+    Ftoi,
+    And,
+    Or,
+    Xor,
+    Not,
+    Shl,
+    Shr,
+    Sar,
+    Mod,
+    Getrem,
+    Jo,
+    Jno,
+    Cmpu,
+    Jgtu,
+    Jltu,
+    Jgqu,
+    Jlqu,
+    Mov,
+    AddI,
+    SubI,
+    MulI,
+    JmpI,
+    JeqI,
+    JneI,
+    JgtI,
+    JltI,
+    JgqI,
+    JlqI,
+    JgtuI,
+    JltuI,
+    JgquI,
+    JlquI,
+    JoI,
+    JnoI,
+    CallI,
+    CallNative,
+    Syscall,
+    Yield,
     Igl,
 }
 
@@ -36,30 +84,223 @@ impl Instruction {
     }
 }
 
+/// The kinds of operand that can follow an opcode in the bytecode
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OperandKind {
+    /// A single byte register number
+    Register,
+    /// A zigzag LEB128 signed immediate
+    Int,
+    /// A LEB128 unsigned immediate
+    UInt,
+    /// The 8 byte IEEE 754 bit pattern of an `f64`
+    Float,
+}
+
+impl Opcode {
+    /// The operands that follow this opcode, in the order they are encoded.
+    /// `decoded::decode_instruction` reads them for the VM based on this.
+    pub fn operands(self) -> &'static [OperandKind] {
+	use self::OperandKind::*;
+	match self {
+	    Opcode::Hlt | Opcode::Ret | Opcode::Yield | Opcode::Igl => &[],
+	    Opcode::Load => &[Register, Int],
+	    Opcode::Loadptr => &[Register, UInt],
+	    Opcode::LoadF => &[Register, Float],
+	    Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Div | Opcode::Mod | Opcode::Deref => &[Register, Register, Register],
+	    Opcode::AddF | Opcode::SubF | Opcode::MulF | Opcode::DivF => &[Register, Register, Register],
+	    Opcode::And | Opcode::Or | Opcode::Xor | Opcode::Shl | Opcode::Shr | Opcode::Sar => &[Register, Register, Register],
+	    Opcode::Cmp | Opcode::Cmpu | Opcode::Alloc | Opcode::Itof | Opcode::Ftoi | Opcode::Not => &[Register, Register],
+	    Opcode::Jmp | Opcode::Jmpf | Opcode::Jmpb | Opcode::Call | Opcode::Free | Opcode::Getrem => &[Register],
+	    Opcode::Jeq | Opcode::Jne | Opcode::Jgt | Opcode::Jlt | Opcode::Jgq | Opcode::Jlq => &[Register],
+	    Opcode::Jo | Opcode::Jno | Opcode::Jgtu | Opcode::Jltu | Opcode::Jgqu | Opcode::Jlqu => &[Register],
+	    Opcode::Write => &[Register, Register, Int],
+	    Opcode::Mov => &[Register, Register],
+	    Opcode::AddI | Opcode::SubI | Opcode::MulI => &[Register, Register, Int],
+	    Opcode::JmpI | Opcode::JeqI | Opcode::JneI | Opcode::JgtI | Opcode::JltI | Opcode::JgqI | Opcode::JlqI => &[UInt],
+	    Opcode::JgtuI | Opcode::JltuI | Opcode::JgquI | Opcode::JlquI | Opcode::JoI | Opcode::JnoI | Opcode::CallI => &[UInt],
+	    Opcode::CallNative | Opcode::Syscall => &[UInt],
+	    Opcode::WritePtr => &[Register, Register, UInt],
+	}
+    }
+
+    /// The variant of a jump or `Call` that takes its target as an immediate
+    /// instead of a register. The assembler switches to it when the operand
+    /// is a label or number.
+    pub fn immediate_form(self) -> Option<Opcode> {
+	match self {
+	    Opcode::Jmp => Some(Opcode::JmpI),
+	    Opcode::Jeq => Some(Opcode::JeqI),
+	    Opcode::Jne => Some(Opcode::JneI),
+	    Opcode::Jgt => Some(Opcode::JgtI),
+	    Opcode::Jlt => Some(Opcode::JltI),
+	    Opcode::Jgq => Some(Opcode::JgqI),
+	    Opcode::Jlq => Some(Opcode::JlqI),
+	    Opcode::Jgtu => Some(Opcode::JgtuI),
+	    Opcode::Jltu => Some(Opcode::JltuI),
+	    Opcode::Jgqu => Some(Opcode::JgquI),
+	    Opcode::Jlqu => Some(Opcode::JlquI),
+	    Opcode::Jo => Some(Opcode::JoI),
+	    Opcode::Jno => Some(Opcode::JnoI),
+	    Opcode::Call => Some(Opcode::CallI),
+	    _ => None,
+	}
+    }
+
+    /// Whether this is a jump or call whose target is an immediate address
+    pub fn has_immediate_target(self) -> bool {
+	matches!(self, Opcode::JmpI | Opcode::JeqI | Opcode::JneI | Opcode::JgtI | Opcode::JltI | Opcode::JgqI | Opcode::JlqI |
+		 Opcode::JgtuI | Opcode::JltuI | Opcode::JgquI | Opcode::JlquI | Opcode::JoI | Opcode::JnoI | Opcode::CallI)
+    }
+
+    /// The name the assembler knows this opcode by
+    pub fn mnemonic(self) -> &'static str {
+	match self {
+	    Opcode::Hlt => "hlt",
+	    Opcode::Load => "load",
+	    Opcode::Add => "add",
+	    Opcode::Sub => "sub",
+	    Opcode::Mul => "mul",
+	    Opcode::Div => "div",
+	    Opcode::Jmp => "jmp",
+	    Opcode::Jmpf => "jmpf",
+	    Opcode::Jmpb => "jmpb",
+	    Opcode::Cmp => "cmp",
+	    Opcode::Jeq => "jeq",
+	    Opcode::Jne => "jne",
+	    Opcode::Jgt => "jgt",
+	    Opcode::Jlt => "jlt",
+	    Opcode::Jgq => "jgq",
+	    Opcode::Jlq => "jlq",
+	    Opcode::Write => "write",
+	    Opcode::WritePtr => "writeptr",
+	    Opcode::Loadptr => "loadptr",
+	    Opcode::Deref => "deref",
+	    Opcode::Call => "call",
+	    Opcode::Ret => "ret",
+	    Opcode::Alloc => "alloc",
+	    Opcode::Free => "free",
+	    Opcode::LoadF => "loadf",
+	    Opcode::AddF => "addf",
+	    Opcode::SubF => "subf",
+	    Opcode::MulF => "mulf",
+	    Opcode::DivF => "divf",
+	    Opcode::Itof => "itof",
+	    Opcode::Ftoi => "ftoi",
+	    Opcode::And => "and",
+	    Opcode::Or => "or",
+	    Opcode::Xor => "xor",
+	    Opcode::Not => "not",
+	    Opcode::Shl => "shl",
+	    Opcode::Shr => "shr",
+	    Opcode::Sar => "sar",
+	    Opcode::Mod => "mod",
+	    Opcode::Getrem => "getrem",
+	    Opcode::Jo => "jo",
+	    Opcode::Jno => "jno",
+	    Opcode::Cmpu => "cmpu",
+	    Opcode::Jgtu => "jgtu",
+	    Opcode::Jltu => "jltu",
+	    Opcode::Jgqu => "jgqu",
+	    Opcode::Jlqu => "jlqu",
+	    Opcode::Mov => "mov",
+	    Opcode::AddI => "addi",
+	    Opcode::SubI => "subi",
+	    Opcode::MulI => "muli",
+	    Opcode::JmpI => "jmpi",
+	    Opcode::JeqI => "jeqi",
+	    Opcode::JneI => "jnei",
+	    Opcode::JgtI => "jgti",
+	    Opcode::JltI => "jlti",
+	    Opcode::JgqI => "jgqi",
+	    Opcode::JlqI => "jlqi",
+	    Opcode::JgtuI => "jgtui",
+	    Opcode::JltuI => "jltui",
+	    Opcode::JgquI => "jgqui",
+	    Opcode::JlquI => "jlqui",
+	    Opcode::JoI => "joi",
+	    Opcode::JnoI => "jnoi",
+	    Opcode::CallI => "calli",
+	    Opcode::CallNative => "callnative",
+	    Opcode::Syscall => "syscall",
+	    Opcode::Yield => "yield",
+	    Opcode::Igl => "igl",
+	}
+    }
+}
+
 impl From<u8> for Opcode {
     fn from(v: u8) -> Self {
         match v {
-            0 => return Opcode::Hlt,
-	    1 => return Opcode::Load,
-	    2 => return Opcode::Add,
-	    3 => return Opcode::Sub,
-	    4 => return Opcode::Mul,
-	    5 => return Opcode::Div,
-	    6 => return Opcode::Jmp,
-	    7 => return Opcode::Jmpf,
-	    8 => return Opcode::Jmpb,
-	    9 => return Opcode::Cmp,
-	    10 => return Opcode::Jeq,
-	    11 => return Opcode::Jne,
-	    12 => return Opcode::Jgt,
-	    13 => return Opcode::Jlt,
-	    14 => return Opcode::Jgq,
-	    15 => return Opcode::Jlq,
-	    16 => return Opcode::Write,
-	    17 => return Opcode::WritePtr,
-	    18 => return Opcode::Loadptr,
-	    19 => return Opcode::Deref,
-	    _ => return Opcode::Igl,
+	    0 => Opcode::Hlt,
+	    1 => Opcode::Load,
+	    2 => Opcode::Add,
+	    3 => Opcode::Sub,
+	    4 => Opcode::Mul,
+	    5 => Opcode::Div,
+	    6 => Opcode::Jmp,
+	    7 => Opcode::Jmpf,
+	    8 => Opcode::Jmpb,
+	    9 => Opcode::Cmp,
+	    10 => Opcode::Jeq,
+	    11 => Opcode::Jne,
+	    12 => Opcode::Jgt,
+	    13 => Opcode::Jlt,
+	    14 => Opcode::Jgq,
+	    15 => Opcode::Jlq,
+	    16 => Opcode::Write,
+	    17 => Opcode::WritePtr,
+	    18 => Opcode::Loadptr,
+	    19 => Opcode::Deref,
+	    20 => Opcode::Call,
+	    21 => Opcode::Ret,
+	    22 => Opcode::Alloc,
+	    23 => Opcode::Free,
+	    24 => Opcode::LoadF,
+	    25 => Opcode::AddF,
+	    26 => Opcode::SubF,
+	    27 => Opcode::MulF,
+	    28 => Opcode::DivF,
+	    29 => Opcode::Itof,
+	    30 => Opcode::Ftoi,
+	    31 => Opcode::And,
+	    32 => Opcode::Or,
+	    33 => Opcode::Xor,
+	    34 => Opcode::Not,
+	    35 => Opcode::Shl,
+	    36 => Opcode::Shr,
+	    37 => Opcode::Sar,
+	    38 => Opcode::Mod,
+	    39 => Opcode::Getrem,
+	    40 => Opcode::Jo,
+	    41 => Opcode::Jno,
+	    42 => Opcode::Cmpu,
+	    43 => Opcode::Jgtu,
+	    44 => Opcode::Jltu,
+	    45 => Opcode::Jgqu,
+	    46 => Opcode::Jlqu,
+	    47 => Opcode::Mov,
+	    48 => Opcode::AddI,
+	    49 => Opcode::SubI,
+	    50 => Opcode::MulI,
+	    51 => Opcode::JmpI,
+	    52 => Opcode::JeqI,
+	    53 => Opcode::JneI,
+	    54 => Opcode::JgtI,
+	    55 => Opcode::JltI,
+	    56 => Opcode::JgqI,
+	    57 => Opcode::JlqI,
+	    58 => Opcode::JgtuI,
+	    59 => Opcode::JltuI,
+	    60 => Opcode::JgquI,
+	    61 => Opcode::JlquI,
+	    62 => Opcode::JoI,
+	    63 => Opcode::JnoI,
+	    64 => Opcode::CallI,
+	    65 => Opcode::CallNative,
+	    66 => Opcode::Syscall,
+	    67 => Opcode::Yield,
+	    _ => Opcode::Igl,
         }
     }
 }
@@ -86,6 +327,54 @@ impl<'a> From<CompleteStr<'a>> for Opcode {
 	    CompleteStr("writeptr") => Opcode::WritePtr,
 	    CompleteStr("loadptr") => Opcode::Loadptr,
 	    CompleteStr("deref") => Opcode::Deref,
+	    CompleteStr("call") => Opcode::Call,
+	    CompleteStr("ret") => Opcode::Ret,
+	    CompleteStr("alloc") => Opcode::Alloc,
+	    CompleteStr("free") => Opcode::Free,
+	    CompleteStr("loadf") => Opcode::LoadF,
+	    CompleteStr("addf") => Opcode::AddF,
+	    CompleteStr("subf") => Opcode::SubF,
+	    CompleteStr("mulf") => Opcode::MulF,
+	    CompleteStr("divf") => Opcode::DivF,
+	    CompleteStr("itof") => Opcode::Itof,
+	    CompleteStr("ftoi") => Opcode::Ftoi,
+	    CompleteStr("and") => Opcode::And,
+	    CompleteStr("or") => Opcode::Or,
+	    CompleteStr("xor") => Opcode::Xor,
+	    CompleteStr("not") => Opcode::Not,
+	    CompleteStr("shl") => Opcode::Shl,
+	    CompleteStr("shr") => Opcode::Shr,
+	    CompleteStr("sar") => Opcode::Sar,
+	    CompleteStr("mod") => Opcode::Mod,
+	    CompleteStr("getrem") => Opcode::Getrem,
+	    CompleteStr("jo") => Opcode::Jo,
+	    CompleteStr("jno") => Opcode::Jno,
+	    CompleteStr("cmpu") => Opcode::Cmpu,
+	    CompleteStr("jgtu") => Opcode::Jgtu,
+	    CompleteStr("jltu") => Opcode::Jltu,
+	    CompleteStr("jgqu") => Opcode::Jgqu,
+	    CompleteStr("jlqu") => Opcode::Jlqu,
+	    CompleteStr("mov") => Opcode::Mov,
+	    CompleteStr("addi") => Opcode::AddI,
+	    CompleteStr("subi") => Opcode::SubI,
+	    CompleteStr("muli") => Opcode::MulI,
+	    CompleteStr("jmpi") => Opcode::JmpI,
+	    CompleteStr("jeqi") => Opcode::JeqI,
+	    CompleteStr("jnei") => Opcode::JneI,
+	    CompleteStr("jgti") => Opcode::JgtI,
+	    CompleteStr("jlti") => Opcode::JltI,
+	    CompleteStr("jgqi") => Opcode::JgqI,
+	    CompleteStr("jlqi") => Opcode::JlqI,
+	    CompleteStr("jgtui") => Opcode::JgtuI,
+	    CompleteStr("jltui") => Opcode::JltuI,
+	    CompleteStr("jgqui") => Opcode::JgquI,
+	    CompleteStr("jlqui") => Opcode::JlquI,
+	    CompleteStr("joi") => Opcode::JoI,
+	    CompleteStr("jnoi") => Opcode::JnoI,
+	    CompleteStr("calli") => Opcode::CallI,
+	    CompleteStr("callnative") => Opcode::CallNative,
+	    CompleteStr("syscall") => Opcode::Syscall,
+	    CompleteStr("yield") => Opcode::Yield,
             _ => Opcode::Igl,
         }
     }
@@ -106,5 +395,24 @@ mod tests {
         let instruction = Instruction::new(Opcode::Hlt);
         assert_eq!(instruction.opcode, Opcode::Hlt);
     }
-}
 
+    #[test]
+    fn test_call_ret_mnemonics() {
+	assert_eq!(Opcode::from(CompleteStr("call")), Opcode::Call);
+	assert_eq!(Opcode::from(CompleteStr("ret")), Opcode::Ret);
+	assert_eq!(Opcode::from(20), Opcode::Call);
+	assert_eq!(Opcode::from(21), Opcode::Ret);
+    }
+
+    #[test]
+    fn test_mnemonics_round_trip() {
+	for byte in 0..=255u8 {
+	    let opcode = Opcode::from(byte);
+	    if opcode == Opcode::Igl {
+		continue;
+	    }
+	    assert_eq!(opcode as u8, byte);
+	    assert_eq!(Opcode::from(CompleteStr(opcode.mnemonic())), opcode);
+	}
+    }
+}
@@ -0,0 +1,17 @@
+pub mod vm;
+pub mod heap;
+pub mod instruction;
+pub mod repl;
+pub mod assembler;
+pub mod bytecode;
+pub mod encoding;
+pub mod decoded;
+pub mod native;
+pub mod syscall;
+pub mod disassembler;
+pub mod debugger;
+pub mod trace;
+pub mod profile;
+
+#[macro_use]
+extern crate nom;
@@ -1,12 +1,227 @@
-pub mod vm;
-pub mod instruction;
-pub mod repl;
-pub mod assembler;
-
 #[macro_use]
-extern crate nom;
+extern crate clap;
+
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::Path;
+use std::process;
+
+use bedrock::assembler::Assembler;
+use bedrock::bytecode::{self, Binary};
+use bedrock::disassembler;
+use bedrock::profile::{Profiler, Weight};
+use bedrock::repl;
+use bedrock::trace::{self, BinaryTracer, JsonTracer, TraceEvent};
+use bedrock::vm::{ArithMode, VM};
+
+/// The program halted or ran off the end of its code
+const EXIT_OK: i32 = 0;
+/// The VM stopped on an error
+const EXIT_VM_ERROR: i32 = 1;
+/// The input could not be read, assembled or loaded
+const EXIT_BAD_INPUT: i32 = 2;
+/// `diff` found the traces differ
+const EXIT_TRACES_DIFFER: i32 = 1;
 
 fn main() {
-    let mut repl = repl::REPL::new();
-    repl.run();
+    let yaml = load_yaml!("cli.yaml");
+    let matches = clap::App::from_yaml(yaml).get_matches();
+    let code = match matches.subcommand() {
+        ("run", Some(m)) => run(m),
+        ("asm", Some(m)) => asm(m.value_of("INPUT_FILE").unwrap(), m.value_of("OUTPUT_FILE")),
+        ("disasm", Some(m)) => disasm(m.value_of("INPUT_FILE").unwrap()),
+        ("replay", Some(m)) => replay(m.value_of("TRACE_FILE").unwrap()),
+        ("diff", Some(m)) => diff(m.value_of("FIRST").unwrap(), m.value_of("SECOND").unwrap()),
+        _ => {
+            let mut repl = repl::REPL::new();
+            repl.run();
+            EXIT_OK
+        }
+    };
+    process::exit(code);
+}
+
+/// Reads `path` and turns it into a bytecode binary, assembling it first
+/// unless it already is one
+fn read_binary(path: &str) -> Result<Vec<u8>, i32> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            return Err(EXIT_BAD_INPUT);
+        }
+    };
+    if bytecode::is_binary(&bytes) {
+        return Ok(bytes);
+    }
+    let source = match String::from_utf8(bytes) {
+        Ok(source) => source,
+        Err(_) => {
+            eprintln!("{}: neither a bedrock binary nor UTF-8 source", path);
+            return Err(EXIT_BAD_INPUT);
+        }
+    };
+    let mut assembler = Assembler::new();
+    assembler.assemble_binary(&source).map_err(|errors| {
+        for e in errors {
+            eprintln!("{}: {}", path, e);
+        }
+        EXIT_BAD_INPUT
+    })
+}
+
+/// Maps the `--arith` value, which clap has already validated, to a mode
+fn arith_mode(name: Option<&str>) -> ArithMode {
+    match name {
+        Some("checked") => ArithMode::Checked,
+        Some("saturating") => ArithMode::Saturating,
+        _ => ArithMode::Wrapping,
+    }
+}
+
+/// Runs the program named by the `run` subcommand's arguments, tracing or
+/// profiling it if they ask for that
+fn run(m: &clap::ArgMatches) -> i32 {
+    let path = m.value_of("INPUT_FILE").unwrap();
+    let binary = match read_binary(path) {
+        Ok(binary) => binary,
+        Err(code) => return code,
+    };
+    let mut vm = VM::new();
+    vm.arith_mode = arith_mode(m.value_of("ARITH"));
+    if let Err(e) = vm.load_bytecode(&binary) {
+        eprintln!("{}: {}", path, e);
+        return EXIT_BAD_INPUT;
+    }
+    let trace_path = m.value_of("TRACE_FILE");
+    if let Some(trace_path) = trace_path {
+        let out = match File::create(trace_path) {
+            Ok(file) => BufWriter::new(file),
+            Err(e) => {
+                eprintln!("{}: {}", trace_path, e);
+                return EXIT_BAD_INPUT;
+            }
+        };
+        vm.tracer = Some(if m.value_of("TRACE_FORMAT") == Some("binary") {
+            Box::new(BinaryTracer::new(out))
+        } else {
+            Box::new(JsonTracer::new(out))
+        });
+    }
+    let folded_path = m.value_of("FOLDED_FILE");
+    if m.is_present("PROFILE") || folded_path.is_some() {
+        vm.profiler = Some(Profiler::new());
+    }
+    let code = match vm.run_decoded() {
+        Ok(()) => EXIT_OK,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            EXIT_VM_ERROR
+        }
+    };
+    if let (Some(tracer), Some(trace_path)) = (&mut vm.tracer, trace_path) {
+        if let Err(e) = tracer.finish() {
+            eprintln!("{}: {}", trace_path, e);
+        }
+    }
+    if let Some(profiler) = &vm.profiler {
+        if m.is_present("PROFILE") {
+            eprint!("{}", profiler.report(&vm.symbols));
+        }
+        if let Some(folded_path) = folded_path {
+            let weight = match m.value_of("FOLDED_WEIGHT") {
+                Some("time") => Weight::Nanoseconds,
+                _ => Weight::Instructions,
+            };
+            if let Err(e) = fs::write(folded_path, profiler.folded_stacks(&vm.symbols, weight)) {
+                eprintln!("{}: {}", folded_path, e);
+            }
+        }
+    }
+    code
+}
+
+/// Reads a trace written by `run --trace`
+fn read_trace(path: &str) -> Result<Vec<TraceEvent>, i32> {
+    let bytes = fs::read(path).map_err(|e| {
+        eprintln!("{}: {}", path, e);
+        EXIT_BAD_INPUT
+    })?;
+    trace::read_trace(&bytes).map_err(|e| {
+        eprintln!("{}: {}", path, e);
+        EXIT_BAD_INPUT
+    })
+}
+
+fn replay(path: &str) -> i32 {
+    let events = match read_trace(path) {
+        Ok(events) => events,
+        Err(code) => return code,
+    };
+    for event in events {
+        println!("{}", event);
+    }
+    EXIT_OK
+}
+
+fn diff(first: &str, second: &str) -> i32 {
+    let (a, b) = match (read_trace(first), read_trace(second)) {
+        (Ok(a), Ok(b)) => (a, b),
+        (Err(code), _) | (_, Err(code)) => return code,
+    };
+    let i = match trace::diff(&a, &b) {
+        Some(i) => i,
+        None => {
+            println!("traces are identical ({} instructions)", a.len());
+            return EXIT_OK;
+        }
+    };
+    println!("traces differ at instruction {}", i);
+    for (path, events) in &[(first, &a), (second, &b)] {
+        match events.get(i) {
+            Some(event) => println!("{}: {}", path, event),
+            None => println!("{}: <end of trace>", path),
+        }
+    }
+    EXIT_TRACES_DIFFER
+}
+
+fn asm(path: &str, output: Option<&str>) -> i32 {
+    let binary = match read_binary(path) {
+        Ok(binary) => binary,
+        Err(code) => return code,
+    };
+    let output = match output {
+        Some(output) => output.to_string(),
+        None => Path::new(path).with_extension("bin").to_string_lossy().into_owned(),
+    };
+    if let Err(e) = fs::write(&output, binary) {
+        eprintln!("{}: {}", output, e);
+        return EXIT_BAD_INPUT;
+    }
+    EXIT_OK
+}
+
+fn disasm(path: &str) -> i32 {
+    let bytes = match read_binary(path) {
+        Ok(bytes) => bytes,
+        Err(code) => return code,
+    };
+    let binary = match Binary::parse(&bytes) {
+        Ok(binary) => binary,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            return EXIT_BAD_INPUT;
+        }
+    };
+    match disassembler::listing(&binary.code, &binary.symbols) {
+        Ok(text) => {
+            print!("{}", text);
+            EXIT_OK
+        },
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            EXIT_BAD_INPUT
+        }
+    }
 }
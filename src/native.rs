@@ -0,0 +1,108 @@
+//! Rust functions exposed to bedrock programs.
+//!
+//! The host registers functions on the VM by name. A program declares the
+//! ones it needs with `.extern name` and calls them with `callnative @name`;
+//! when a binary is loaded each extern is linked to the registered function
+//! of the same name.
+//!
+//! Calling convention: a function registered with arity `n` is passed `r0`
+//! to `r(n-1)` as its arguments and its result is written to `r0`. No other
+//! register is touched.
+
+use std::collections::HashMap;
+
+use crate::heap::Heap;
+use crate::vm::Val;
+
+/// A host function. Besides its arguments it gets the VM's heap so it can
+/// follow pointers. An `Err` stops the program with the message.
+pub type NativeFn = Box<dyn FnMut(&[Val], &mut Heap) -> Result<Val, String>>;
+
+pub struct NativeFunction {
+    pub name: String,
+    pub arity: usize,
+    function: NativeFn,
+}
+
+impl NativeFunction {
+    pub fn call(&mut self, args: &[Val], heap: &mut Heap) -> Result<Val, String> {
+	(self.function)(args, heap)
+    }
+}
+
+/// Registered host functions, indexed in registration order
+#[derive(Default)]
+pub struct NativeRegistry {
+    functions: Vec<NativeFunction>,
+    by_name: HashMap<String, usize>,
+}
+
+impl NativeRegistry {
+    pub fn new() -> NativeRegistry {
+	NativeRegistry {
+	    functions: vec![],
+	    by_name: HashMap::new(),
+	}
+    }
+
+    /// Registers `function` as `name` and returns its index. Registering a
+    /// name again replaces the earlier function but keeps its index.
+    ///
+    /// Panics if `arity` is more than the VM's 256 registers.
+    pub fn register<F>(&mut self, name: &str, arity: usize, function: F) -> usize
+    where
+	F: FnMut(&[Val], &mut Heap) -> Result<Val, String> + 'static,
+    {
+	assert!(arity <= 256, "native function {} takes more arguments than there are registers", name);
+	let native = NativeFunction {
+	    name: name.to_string(),
+	    arity,
+	    function: Box::new(function),
+	};
+	match self.by_name.get(name) {
+	    Some(&index) => {
+		self.functions[index] = native;
+		index
+	    },
+	    None => {
+		self.functions.push(native);
+		self.by_name.insert(name.to_string(), self.functions.len() - 1);
+		self.functions.len() - 1
+	    },
+	}
+    }
+
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+	self.by_name.get(name).cloned()
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut NativeFunction> {
+	self.functions.get_mut(index)
+    }
+
+    pub fn len(&self) -> usize {
+	self.functions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+	self.functions.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register() {
+	let mut natives = NativeRegistry::new();
+	assert_eq!(natives.register("double", 1, |args, _| Ok(Val::Int(args[0].as_int() * 2))), 0);
+	assert_eq!(natives.register("zero", 0, |_, _| Ok(Val::Int(0))), 1);
+	assert_eq!(natives.register("double", 1, |args, _| Ok(Val::Int(args[0].as_int() + args[0].as_int()))), 0);
+	assert_eq!(natives.len(), 2);
+	assert_eq!(natives.index_of("zero"), Some(1));
+	assert_eq!(natives.index_of("missing"), None);
+	let mut heap = Heap::new();
+	assert_eq!(natives.get_mut(0).unwrap().call(&[Val::Int(21)], &mut heap), Ok(Val::Int(42)));
+    }
+}
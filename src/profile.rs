@@ -0,0 +1,250 @@
+//! Instruction level profiling.
+//!
+//! With a `Profiler` set on the VM, every instruction it executes is counted
+//! and timed by address. Counts and times per opcode and per label are
+//! worked out from those when a report is asked for; an instruction belongs
+//! to the nearest code label at or before it.
+//!
+//! The profiler also follows `Call` and `Ret`, so it can write the time spent
+//! under each chain of calls as folded stacks, the input format of
+//! flamegraph.pl and inferno. Each call is named after the label of its
+//! target and the outermost frame after the label where execution began.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::assembler::symbols::{SymbolTable, SymbolType};
+use crate::decoded::DecodedInstruction;
+use crate::instruction::Opcode;
+use crate::vm::Frame;
+
+/// Name of the code before the first label
+const UNLABELLED: &str = "(start)";
+
+/// Counts for one address
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct PcStats {
+    pub opcode: Opcode,
+    pub count: u64,
+    pub time: Duration,
+}
+
+/// One line of a report
+#[derive(Debug, PartialEq, Clone)]
+pub struct Row {
+    pub name: String,
+    pub count: u64,
+    pub time: Duration,
+}
+
+/// What the numbers in folded stacks measure
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Weight {
+    Instructions,
+    Nanoseconds,
+}
+
+/// A chain of calls, identified by the address each call went to
+#[derive(Debug)]
+struct StackNode {
+    address: usize,
+    parent: usize,
+    children: HashMap<usize, usize>,
+    count: u64,
+    time: Duration,
+}
+
+#[derive(Debug, Default)]
+pub struct Profiler {
+    pcs: BTreeMap<usize, PcStats>,
+    /// The root of the call tree, once something has run, and its callees
+    nodes: Vec<StackNode>,
+    current: usize,
+    depth: usize,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+	Profiler::default()
+    }
+
+    /// Counts `ins`, which took `time` to execute and left the VM with
+    /// `call_stack`
+    pub fn record(&mut self, ins: &DecodedInstruction, time: Duration, call_stack: &[Frame]) {
+	let stats = self.pcs.entry(ins.address).or_insert(PcStats { opcode: ins.opcode, count: 0, time: Duration::default() });
+	stats.count += 1;
+	stats.time += time;
+	if self.nodes.is_empty() {
+	    self.nodes.push(StackNode { address: ins.address, parent: 0, children: HashMap::new(), count: 0, time: Duration::default() });
+	}
+	let node = &mut self.nodes[self.current];
+	node.count += 1;
+	node.time += time;
+	while self.depth > call_stack.len() {
+	    self.current = self.nodes[self.current].parent;
+	    self.depth -= 1;
+	}
+	while self.depth < call_stack.len() {
+	    self.current = self.child(self.current, call_stack[self.depth].target);
+	    self.depth += 1;
+	}
+    }
+
+    fn child(&mut self, parent: usize, address: usize) -> usize {
+	if let Some(&child) = self.nodes[parent].children.get(&address) {
+	    return child;
+	}
+	let child = self.nodes.len();
+	self.nodes.push(StackNode { address, parent, children: HashMap::new(), count: 0, time: Duration::default() });
+	self.nodes[parent].children.insert(address, child);
+	child
+    }
+
+    /// The number of instructions executed
+    pub fn total(&self) -> u64 {
+	self.pcs.values().map(|s| s.count).sum()
+    }
+
+    /// The time spent executing them
+    pub fn total_time(&self) -> Duration {
+	self.pcs.values().map(|s| s.time).sum()
+    }
+
+    /// Counts for every address that was executed, by address
+    pub fn by_pc(&self) -> impl Iterator<Item = (usize, &PcStats)> + '_ {
+	self.pcs.iter().map(|(pc, stats)| (*pc, stats))
+    }
+
+    /// Counts per opcode, most executed first
+    pub fn by_opcode(&self) -> Vec<Row> {
+	sorted(self.pcs.values().map(|s| (s.opcode.mnemonic().to_string(), s.count, s.time)))
+    }
+
+    /// Counts per label, most executed first
+    pub fn by_label(&self, symbols: &SymbolTable) -> Vec<Row> {
+	let labels = Labels::new(symbols);
+	sorted(self.pcs.iter().map(|(pc, s)| (labels.enclosing(*pc).to_string(), s.count, s.time)))
+    }
+
+    /// A readable summary, each table sorted with the most executed first
+    pub fn report(&self, symbols: &SymbolTable) -> String {
+	let total = self.total();
+	let mut out = String::new();
+	let _ = writeln!(out, "{} instructions in {:?}", total, self.total_time());
+	let pcs: Vec<Row> = sorted(self.pcs.iter().map(|(pc, s)| (format!("{:04} {}", pc, s.opcode.mnemonic()), s.count, s.time)));
+	for (title, rows) in &[("label", self.by_label(symbols)), ("opcode", self.by_opcode()), ("pc", pcs)] {
+	    let _ = writeln!(out, "\n{:>12} {:>7} {:>14}  {}", "count", "%", "time", title);
+	    for row in rows {
+		let percent = 100.0 * row.count as f64 / total as f64;
+		let _ = writeln!(out, "{:>12} {:>6.2}% {:>14}  {}", row.count, percent, format!("{:?}", row.time), row.name);
+	    }
+	}
+	out
+    }
+
+    /// Every chain of calls that executed something, one per line as its
+    /// frames separated by `;` and how much it executed
+    pub fn folded_stacks(&self, symbols: &SymbolTable, weight: Weight) -> String {
+	let labels = Labels::new(symbols);
+	let mut lines = vec![];
+	for (i, node) in self.nodes.iter().enumerate() {
+	    if node.count == 0 {
+		continue;
+	    }
+	    let value = match weight {
+		Weight::Instructions => u128::from(node.count),
+		Weight::Nanoseconds => node.time.as_nanos(),
+	    };
+	    let mut frames = vec![labels.enclosing(node.address)];
+	    let mut n = i;
+	    while n != 0 {
+		n = self.nodes[n].parent;
+		frames.push(labels.enclosing(self.nodes[n].address));
+	    }
+	    frames.reverse();
+	    lines.push(format!("{} {}\n", frames.join(";"), value));
+	}
+	lines.sort();
+	lines.concat()
+    }
+}
+
+/// Sums the counts and times for each name and sorts the result, most
+/// executed first
+fn sorted<I: Iterator<Item = (String, u64, Duration)>>(rows: I) -> Vec<Row> {
+    let mut sums: BTreeMap<String, (u64, Duration)> = BTreeMap::new();
+    for (name, count, time) in rows {
+	let sum = sums.entry(name).or_default();
+	sum.0 += count;
+	sum.1 += time;
+    }
+    let mut rows: Vec<Row> = sums.into_iter().map(|(name, (count, time))| Row { name, count, time }).collect();
+    rows.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+    rows
+}
+
+/// Code labels sorted by address
+struct Labels<'a> {
+    labels: Vec<(usize, &'a str)>,
+}
+
+impl<'a> Labels<'a> {
+    fn new(symbols: &'a SymbolTable) -> Labels<'a> {
+	let mut labels: Vec<(usize, &str)> = symbols.symbols().iter()
+	    .filter(|s| s.symbol_type == SymbolType::Label)
+	    .map(|s| (s.offset as usize, s.name.as_str()))
+	    .collect();
+	labels.sort();
+	Labels { labels }
+    }
+
+    /// The last label at or before `address`
+    fn enclosing(&self, address: usize) -> &'a str {
+	match self.labels.iter().rev().find(|(offset, _)| *offset <= address) {
+	    Some((_, name)) => name,
+	    None => UNLABELLED,
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::VM;
+
+    fn profile(source: &str) -> (Profiler, SymbolTable) {
+	let mut asm = Assembler::new();
+	let mut vm = VM::new();
+	vm.program = asm.assemble(source).unwrap();
+	vm.profiler = Some(Profiler::new());
+	vm.run().unwrap();
+	(vm.profiler.unwrap(), asm.symbols)
+    }
+
+    const PROGRAM: &str = "load r0 3\nloop: call @f\nsubi r0 r0 1\nload r1 0\ncmp r0 r1\njgt @loop\nhlt\nf: call @g\nret\ng: addi r2 r2 1\nret\n";
+
+    #[test]
+    fn test_counts() {
+	let (profiler, symbols) = profile(PROGRAM);
+	// load, 3 * (call subi load cmp jgt), hlt, 3 * (call ret), 3 * (addi ret)
+	assert_eq!(profiler.total(), 1 + 15 + 1 + 6 + 6);
+	let by_label = profiler.by_label(&symbols);
+	assert_eq!(by_label[0].name, "loop");
+	assert_eq!(by_label[0].count, 16);
+	assert_eq!(by_label.iter().map(|r| (r.name.as_str(), r.count)).collect::<Vec<_>>(), vec![("loop", 16), ("f", 6), ("g", 6), ("(start)", 1)]);
+	let by_opcode = profiler.by_opcode();
+	assert_eq!((by_opcode[0].name.as_str(), by_opcode[0].count), ("calli", 6));
+	assert_eq!(profiler.by_pc().next().map(|(pc, s)| (pc, s.count)), Some((0, 1)));
+	let report = profiler.report(&symbols);
+	assert!(report.starts_with("29 instructions in"));
+	assert!(report.contains("opcode\n"));
+    }
+
+    #[test]
+    fn test_folded_stacks() {
+	let (profiler, symbols) = profile(PROGRAM);
+	assert_eq!(profiler.folded_stacks(&symbols, Weight::Instructions), "(start) 17\n(start);f 6\n(start);f;g 6\n");
+    }
+}
@@ -1,14 +1,25 @@
 use std;
 use std::io;
 use std::io::Write;
+use std::fs;
 use std::num::ParseIntError;
 use crate::vm::VM;
-use crate::assembler::program_parsers::program;
+use crate::assembler::Assembler;
+use crate::bytecode;
+use crate::debugger::{Debugger, StopReason, Watch};
+use crate::disassembler;
 
 pub struct REPL {
     command_buffer: Vec<String>,
 
     vm:VM,
+    debugger: Debugger,
+}
+
+impl Default for REPL {
+    fn default() -> Self {
+	REPL::new()
+    }
 }
 
 impl REPL {
@@ -16,6 +27,7 @@ impl REPL {
     pub fn new() -> REPL {
         REPL {
             vm: VM::new(),
+	    debugger: Debugger::new(),
             command_buffer: vec![]
         }
     }
@@ -24,7 +36,7 @@ impl REPL {
 	let split = i.split(" ").collect::<Vec<&str>>();
 	let mut results: Vec<u8> = vec![];
 	for hex_string in split {
-            let byte = u8::from_str_radix(&hex_string, 16);
+	    let byte = u8::from_str_radix(hex_string, 16);
             match byte {
 		Ok(result) => {
                     results.push(result);
@@ -68,8 +80,9 @@ impl REPL {
 		},
 		".program" => {
 		    println!("Listing instructions currently in VM's program vector:");
-		    for instruction in &self.vm.program {
-			println!("{}", instruction);
+		    match disassembler::listing(&self.vm.program, &self.vm.symbols) {
+			Ok(text) => print!("{}", text),
+			Err(e) => println!("{}", e),
 		    }
 		    println!("End of Program Listing");
 		},
@@ -82,25 +95,155 @@ impl REPL {
 		    println!("heap");
 		    println!("{:#?}", self.vm.heap);
 		},
+		".gc" => {
+		    println!("{}", self.vm.heap.stats());
+		},
+		".gc run" => {
+		    let freed = self.vm.collect_garbage();
+		    println!("freed {} blocks", freed);
+		    println!("{}", self.vm.heap.stats());
+		},
 		"" => {
 		    break;
 		},
 		_ => {
-		    // You can assign the result of a match to a variable
-		    // Rust can convert types using `Into` and `From`
-		    let program = match program(buffer.into()) {
-			// Rusts pattern matching is pretty powerful an can even be nested
-			Ok((_,  program)) => program,
-			Err(_) => {
-			    println!("Unable to parse input");
+		    if self.debug_command(buffer) {
+			continue;
+		    }
+		    // Each line is assembled where it will land, so labels declared on
+		    // earlier lines can be jumped back to
+		    let mut assembler = Assembler::new();
+		    let origin = self.vm.program.len() as u64;
+		    let mut bytes = match assembler.assemble_at(buffer, origin, self.vm.symbols.clone()) {
+			Ok(bytes) => bytes,
+			Err(errors) => {
+			    for e in errors {
+				println!("{}", e);
+			    }
 			    continue;
 			}
 		    };
 		    // The `program` is `pub` anyways so you can just `append` to the `Vec`
-		    self.vm.program.append(&mut program.to_bytes());
-		    self.vm.execute_instruction();
+		    self.vm.program.append(&mut bytes);
+		    self.vm.symbols = assembler.symbols;
+		    self.vm.link_natives();
+		    if let Err(e) = self.vm.execute_instruction() {
+			println!("{}", e);
+		    }
 		}
 	    }
 	}
     }
+
+    /// Handles the debugger's commands, returning false for anything else:
+    ///
+    /// ```text
+    /// .load <file>                   load a source file or binary without running it
+    /// .break <address|label>         stop before the instruction there
+    /// .delete <address|label>        remove a breakpoint
+    /// .watch r<n> | <block> <offset> stop when a register or heap cell changes
+    /// .unwatch r<n> | <block> <offset>
+    /// .breakpoints                   list breakpoints and watchpoints
+    /// .step, .next, .continue        execute one instruction, step over calls, or run
+    /// .where                         show the next instruction
+    /// ```
+    fn debug_command(&mut self, command: &str) -> bool {
+	let words: Vec<&str> = command.split_whitespace().collect();
+	match words.as_slice() {
+	    [".load", path] => {
+		match self.load_file(path) {
+		    Ok(()) => {
+			self.debugger.reset();
+			println!("{}", Debugger::current_instruction(&self.vm));
+		    },
+		    Err(e) => println!("{}", e),
+		}
+	    },
+	    [".break", place] => match self.address(place) {
+		Some(address) => {
+		    self.debugger.add_breakpoint(address);
+		    println!("breakpoint at {}", address);
+		},
+		None => println!("no label or address {}", place),
+	    },
+	    [".delete", place] => match self.address(place) {
+		Some(address) if self.debugger.remove_breakpoint(address) => println!("deleted breakpoint at {}", address),
+		_ => println!("no breakpoint at {}", place),
+	    },
+	    [".watch", rest @ ..] => match REPL::watch(rest) {
+		Some(watch) => {
+		    self.debugger.add_watchpoint(&self.vm, watch);
+		    println!("watching {}", watch);
+		},
+		None => println!("expected r<n> or <block> <offset>"),
+	    },
+	    [".unwatch", rest @ ..] => match REPL::watch(rest) {
+		Some(watch) if self.debugger.remove_watchpoint(watch) => println!("stopped watching {}", watch),
+		Some(watch) => println!("{} is not being watched", watch),
+		None => println!("expected r<n> or <block> <offset>"),
+	    },
+	    [".breakpoints"] => {
+		for address in self.debugger.breakpoints() {
+		    println!("breakpoint at {}", address);
+		}
+		for watch in self.debugger.watchpoints() {
+		    println!("watching {}", watch);
+		}
+	    },
+	    [".step"] => {
+		let reason = self.debugger.step(&mut self.vm);
+		self.report(reason);
+	    },
+	    [".next"] => {
+		let reason = self.debugger.next(&mut self.vm);
+		self.report(reason);
+	    },
+	    [".continue"] => {
+		let reason = self.debugger.cont(&mut self.vm);
+		self.report(reason);
+	    },
+	    [".where"] => println!("{}", Debugger::current_instruction(&self.vm)),
+	    _ => return false,
+	}
+	true
+    }
+
+    fn report(&self, reason: StopReason) {
+	if reason != StopReason::Stepped {
+	    println!("{}", reason);
+	}
+	if reason != StopReason::Halted {
+	    println!("{}", Debugger::current_instruction(&self.vm));
+	}
+    }
+
+    /// A code address given as a number or a label
+    fn address(&self, place: &str) -> Option<usize> {
+	match place.parse::<usize>() {
+	    Ok(address) => Some(address),
+	    Err(_) => self.vm.symbols.symbol_value(place).map(|v| v as usize),
+	}
+    }
+
+    fn watch(words: &[&str]) -> Option<Watch> {
+	match words {
+	    [register] if register.starts_with('r') => register[1..].parse().ok().map(Watch::Register),
+	    [block, offset] => Some(Watch::Cell { block: block.parse().ok()?, offset: offset.parse().ok()? }),
+	    _ => None,
+	}
+    }
+
+    /// Loads a source file or binary into the VM, ready to run from its entry point
+    fn load_file(&mut self, path: &str) -> Result<(), String> {
+	let bytes = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+	let binary = if bytecode::is_binary(&bytes) {
+	    bytes
+	} else {
+	    let source = String::from_utf8(bytes).map_err(|_| format!("{}: neither a bedrock binary nor UTF-8 source", path))?;
+	    Assembler::new().assemble_binary(&source).map_err(|errors| {
+		errors.iter().map(|e| format!("{}: {}", path, e)).collect::<Vec<_>>().join("\n")
+	    })?
+	};
+	self.vm.load_bytecode(&binary).map_err(|e| format!("{}: {}", path, e))
+    }
 }
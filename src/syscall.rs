@@ -0,0 +1,389 @@
+//! The I/O services behind the `Syscall` opcode.
+//!
+//! `syscall n` runs entry `n` of the table below. Like native functions,
+//! syscalls take their arguments from `r0` upwards and leave their result in
+//! `r0`. Strings are heap blocks of byte cells ending with a 0, the layout
+//! `.asciiz` produces.
+//!
+//! ```text
+//! n  name        arguments               result
+//! 0  print_int   r0 integer              -
+//! 1  print_str   r0 string               -
+//! 2  eprint_int  r0 integer              -
+//! 3  eprint_str  r0 string               -
+//! 4  read_line   -                       r0 string without the newline, or -1
+//!                                        at the end of input
+//! 5  open        r0 path, r1 mode        r0 file handle
+//! 6  read        r0 handle, r1 max bytes r0 string, r1 number of bytes read
+//! 7  write       r0 handle, r1 string    r0 number of bytes written
+//! 8  close       r0 handle               -
+//! ```
+//!
+//! `read_line` and `read` stop early rather than read more than fits in one
+//! heap block; the rest is left for the next call.
+//!
+//! File modes are 0 to read, 1 to create or truncate and write, and 2 to
+//! create or append. Each channel in `Io` can be redirected or disabled by
+//! the host; using a disabled one is an error.
+//!
+//! `Io::new`, which `VM::new` installs, hands programs the process's own
+//! streams and lets them open any file the process can. That suits the
+//! command line tools; a host running code it does not trust should start
+//! from `Io::disabled` and enable only what it needs, confining files with
+//! `FileAccess::Within`.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+use crate::heap::{Heap, HeapError};
+use crate::vm::Val;
+
+pub const PRINT_INT: u64 = 0;
+pub const PRINT_STR: u64 = 1;
+pub const EPRINT_INT: u64 = 2;
+pub const EPRINT_STR: u64 = 3;
+pub const READ_LINE: u64 = 4;
+pub const OPEN: u64 = 5;
+pub const READ: u64 = 6;
+pub const WRITE: u64 = 7;
+pub const CLOSE: u64 = 8;
+
+/// Why a syscall failed
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum SyscallError {
+    /// There is no syscall with this number
+    Unknown,
+    /// The call failed, for the reason given
+    Failed(String),
+}
+
+impl From<io::Error> for SyscallError {
+    fn from(e: io::Error) -> Self {
+	SyscallError::Failed(e.to_string())
+    }
+}
+
+/// Which files programs may open
+#[derive(Debug, PartialEq, Clone)]
+pub enum FileAccess {
+    Disabled,
+    /// Any path the process itself could open
+    Unrestricted,
+    /// Only relative paths that stay inside this directory, which they are
+    /// resolved against
+    Within(PathBuf),
+}
+
+/// Where the VM's I/O goes. `None` disables a channel.
+pub struct Io {
+    pub stdout: Option<Box<dyn Write>>,
+    pub stderr: Option<Box<dyn Write>>,
+    pub stdin: Option<Box<dyn BufRead>>,
+    pub files: FileAccess,
+    handles: HashMap<i64, File>,
+    next_handle: i64,
+}
+
+impl Default for Io {
+    fn default() -> Self {
+	Io::new()
+    }
+}
+
+impl Io {
+    /// The process's own standard streams, with unrestricted file access.
+    /// Not a sandbox, see the module documentation.
+    pub fn new() -> Io {
+	Io {
+	    stdout: Some(Box::new(io::stdout())),
+	    stderr: Some(Box::new(io::stderr())),
+	    stdin: Some(Box::new(io::BufReader::new(io::stdin()))),
+	    files: FileAccess::Unrestricted,
+	    handles: HashMap::new(),
+	    next_handle: 0,
+	}
+    }
+
+    /// Every channel disabled
+    pub fn disabled() -> Io {
+	Io {
+	    stdout: None,
+	    stderr: None,
+	    stdin: None,
+	    files: FileAccess::Disabled,
+	    handles: HashMap::new(),
+	    next_handle: 0,
+	}
+    }
+
+    /// Closes every file the program left open
+    pub fn close_all(&mut self) {
+	self.handles.clear();
+    }
+
+    /// Runs syscall `number` against the given registers and heap
+    pub fn syscall(&mut self, number: u64, registers: &mut [Val], heap: &mut Heap) -> Result<(), SyscallError> {
+	match number {
+	    PRINT_INT => write_out(&mut self.stdout, "stdout", registers[0].as_int().to_string().as_bytes()),
+	    PRINT_STR => write_out(&mut self.stdout, "stdout", &read_string(heap, registers[0])?),
+	    EPRINT_INT => write_out(&mut self.stderr, "stderr", registers[0].as_int().to_string().as_bytes()),
+	    EPRINT_STR => write_out(&mut self.stderr, "stderr", &read_string(heap, registers[0])?),
+	    READ_LINE => {
+		let stdin = match &mut self.stdin {
+		    Some(stdin) => stdin,
+		    None => return Err(disabled("stdin")),
+		};
+		let mut line = vec![];
+		if stdin.take(string_capacity(heap)).read_until(b'\n', &mut line)? == 0 {
+		    registers[0] = Val::Int(-1);
+		    return Ok(());
+		}
+		if line.ends_with(b"\n") {
+		    line.pop();
+		    if line.ends_with(b"\r") {
+			line.pop();
+		    }
+		}
+		registers[0] = alloc_string(heap, &line, registers)?;
+		Ok(())
+	    },
+	    OPEN => {
+		let path = String::from_utf8_lossy(&read_string(heap, registers[0])?).into_owned();
+		let path = self.resolve(&path)?;
+		let file = match registers[1].as_int() {
+		    0 => File::open(path)?,
+		    1 => File::create(path)?,
+		    2 => OpenOptions::new().append(true).create(true).open(path)?,
+		    mode => return Err(SyscallError::Failed(format!("unknown file mode {}", mode))),
+		};
+		let handle = self.next_handle;
+		self.next_handle += 1;
+		self.handles.insert(handle, file);
+		registers[0] = Val::Int(handle);
+		Ok(())
+	    },
+	    READ => {
+		let max = std::cmp::min(registers[1].as_int().max(0) as u64, string_capacity(heap));
+		let mut bytes = vec![];
+		self.file(registers[0])?.take(max).read_to_end(&mut bytes)?;
+		registers[0] = alloc_string(heap, &bytes, registers)?;
+		registers[1] = Val::Int(bytes.len() as i64);
+		Ok(())
+	    },
+	    WRITE => {
+		let bytes = read_string(heap, registers[1])?;
+		self.file(registers[0])?.write_all(&bytes)?;
+		registers[0] = Val::Int(bytes.len() as i64);
+		Ok(())
+	    },
+	    CLOSE => {
+		let handle = registers[0].as_int();
+		match self.handles.remove(&handle) {
+		    Some(_) => Ok(()),
+		    None => Err(SyscallError::Failed(format!("no open file with handle {}", handle))),
+		}
+	    },
+	    _ => Err(SyscallError::Unknown),
+	}
+    }
+
+    fn file(&mut self, handle: Val) -> Result<&mut File, SyscallError> {
+	let handle = handle.as_int();
+	match self.handles.get_mut(&handle) {
+	    Some(file) => Ok(file),
+	    None => Err(SyscallError::Failed(format!("no open file with handle {}", handle))),
+	}
+    }
+
+    /// Where `path` points once `files` has been applied
+    fn resolve(&self, path: &str) -> Result<PathBuf, SyscallError> {
+	match &self.files {
+	    FileAccess::Disabled => Err(disabled("file access")),
+	    FileAccess::Unrestricted => Ok(PathBuf::from(path)),
+	    FileAccess::Within(root) => {
+		let path = Path::new(path);
+		if path.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+		    Ok(root.join(path))
+		} else {
+		    Err(SyscallError::Failed(format!("{} is outside the sandbox", path.display())))
+		}
+	    },
+	}
+    }
+}
+
+fn disabled(channel: &str) -> SyscallError {
+    SyscallError::Failed(format!("{} is disabled", channel))
+}
+
+fn write_out(channel: &mut Option<Box<dyn Write>>, name: &str, bytes: &[u8]) -> Result<(), SyscallError> {
+    match channel {
+	Some(out) => {
+	    out.write_all(bytes)?;
+	    out.flush()?;
+	    Ok(())
+	},
+	None => Err(disabled(name)),
+    }
+}
+
+/// The bytes of the string `block` points at, up to its terminating 0 or the
+/// end of the block
+pub fn read_string(heap: &Heap, block: Val) -> Result<Vec<u8>, SyscallError> {
+    let id = block.as_uint();
+    match heap.get(id) {
+	Ok(k) => Ok(k.data.iter().map(|c| c.as_int() as u8).take_while(|b| *b != 0).collect()),
+	Err(HeapError::UnknownBlock) => Err(SyscallError::Failed(format!("unknown heap block {}", id))),
+	Err(_) => Err(SyscallError::Failed(format!("heap block {} was freed", id))),
+    }
+}
+
+/// The most bytes a string stored by `alloc_string` can hold, leaving a cell
+/// for the terminating 0
+fn string_capacity(heap: &Heap) -> u64 {
+    heap.max_block_size.saturating_sub(1) as u64
+}
+
+/// Stores `bytes` in a new block as a 0 terminated string, if the heap's
+/// limits allow it. Like `Alloc`, it collects garbage first when the heap
+/// needs it, keeping whatever `roots` point at.
+pub fn alloc_string(heap: &mut Heap, bytes: &[u8], roots: &[Val]) -> Result<Val, SyscallError> {
+    let result = heap.make_room(bytes.len() + 1, roots).and_then(|_| {
+	let mut cells: Vec<Val> = bytes.iter().map(|b| Val::Int(i64::from(*b))).collect();
+	cells.push(Val::Int(0));
+	heap.try_alloc_with(cells)
+    });
+    match result {
+	Ok(block) => Ok(Val::Ptr(block)),
+	Err(e) => Err(SyscallError::Failed(format!("cannot store {} bytes: {}", bytes.len(), e))),
+    }
+}
+
+/// A `Write` whose contents stay readable after it is handed to `Io`, for
+/// capturing a program's output
+#[derive(Debug, Default, Clone)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn new() -> SharedBuffer {
+	SharedBuffer::default()
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+	self.0.borrow().clone()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+	self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+	Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heap::DEFAULT_MAX_BLOCK_SIZE;
+
+    #[test]
+    fn test_print_and_read_line() {
+	let out = SharedBuffer::new();
+	let mut io = Io::disabled();
+	io.stdout = Some(Box::new(out.clone()));
+	io.stdin = Some(Box::new(io::Cursor::new("hello\r\nlast")));
+	let mut heap = Heap::new();
+	let mut registers = [Val::Int(-12), Val::Int(0)];
+	io.syscall(PRINT_INT, &mut registers, &mut heap).unwrap();
+	io.syscall(READ_LINE, &mut registers, &mut heap).unwrap();
+	io.syscall(PRINT_STR, &mut registers, &mut heap).unwrap();
+	io.syscall(READ_LINE, &mut registers, &mut heap).unwrap();
+	io.syscall(PRINT_STR, &mut registers, &mut heap).unwrap();
+	assert_eq!(out.contents(), b"-12hellolast".to_vec());
+	io.syscall(READ_LINE, &mut registers, &mut heap).unwrap();
+	assert_eq!(registers[0], Val::Int(-1));
+	assert_eq!(io.syscall(EPRINT_INT, &mut registers, &mut heap), Err(SyscallError::Failed("stderr is disabled".to_string())));
+	assert_eq!(io.syscall(99, &mut registers, &mut heap), Err(SyscallError::Unknown));
+    }
+
+    #[test]
+    fn test_strings_collect_garbage() {
+	let mut io = Io::disabled();
+	io.stdin = Some(Box::new(io::Cursor::new("abc\n".repeat(10))));
+	let mut heap = Heap::new();
+	heap.max_cells = 8;
+	let mut registers = [Val::Int(0)];
+	for _ in 0..10 {
+	    io.syscall(READ_LINE, &mut registers, &mut heap).unwrap();
+	}
+	assert_eq!(read_string(&heap, registers[0]), Ok(b"abc".to_vec()));
+	// Nothing to free when every string is still held
+	let mut held = vec![];
+	let error = loop {
+	    match alloc_string(&mut heap, b"abc", &held) {
+		Ok(s) => held.push(s),
+		Err(e) => break e,
+	    }
+	};
+	assert_eq!(held.len(), 2);
+	assert_eq!(error, SyscallError::Failed("cannot store 3 bytes: the heap is limited to 8 cells".to_string()));
+	// Long lines are split to fit in a block
+	heap.max_cells = usize::MAX;
+	heap.max_block_size = 4;
+	io.stdin = Some(Box::new(io::Cursor::new("abcdefg\n")));
+	for part in &[&b"abc"[..], b"def", b"g"] {
+	    io.syscall(READ_LINE, &mut registers, &mut heap).unwrap();
+	    assert_eq!(read_string(&heap, registers[0]), Ok(part.to_vec()));
+	}
+    }
+
+    #[test]
+    fn test_files() {
+	let dir = std::env::temp_dir().join(format!("bedrock-syscall-{}", std::process::id()));
+	std::fs::create_dir_all(&dir).unwrap();
+	let mut io = Io::disabled();
+	io.files = FileAccess::Within(dir.clone());
+	let mut heap = Heap::new();
+	let path = alloc_string(&mut heap, b"out.txt", &[]).unwrap();
+	let text = alloc_string(&mut heap, b"some text", &[path]).unwrap();
+	let mut registers = [path, Val::Int(1), Val::Int(0)];
+	io.syscall(OPEN, &mut registers, &mut heap).unwrap();
+	let handle = registers[0];
+	registers[1] = text;
+	io.syscall(WRITE, &mut registers, &mut heap).unwrap();
+	assert_eq!(registers[0], Val::Int(9));
+	registers[0] = handle;
+	io.syscall(CLOSE, &mut registers, &mut heap).unwrap();
+	assert!(io.syscall(CLOSE, &mut registers, &mut heap).is_err());
+
+	registers = [path, Val::Int(0), Val::Int(0)];
+	io.syscall(OPEN, &mut registers, &mut heap).unwrap();
+	let reader = registers[0];
+	registers[1] = Val::Int(4);
+	io.syscall(READ, &mut registers, &mut heap).unwrap();
+	assert_eq!(read_string(&heap, registers[0]), Ok(b"some".to_vec()));
+	assert_eq!(registers[1], Val::Int(4));
+	// No more than fits in a block, whatever r1 asks for
+	heap.max_block_size = 4;
+	registers[0] = reader;
+	registers[1] = Val::Int(i64::MAX);
+	io.syscall(READ, &mut registers, &mut heap).unwrap();
+	assert_eq!(read_string(&heap, registers[0]), Ok(b" te".to_vec()));
+	heap.max_block_size = DEFAULT_MAX_BLOCK_SIZE;
+
+	registers[0] = alloc_string(&mut heap, b"../escape.txt", &[path]).unwrap();
+	registers[1] = Val::Int(1);
+	assert!(io.syscall(OPEN, &mut registers, &mut heap).is_err());
+	io.files = FileAccess::Disabled;
+	registers[0] = path;
+	assert_eq!(io.syscall(OPEN, &mut registers, &mut heap), Err(SyscallError::Failed("file access is disabled".to_string())));
+	std::fs::remove_dir_all(dir).unwrap();
+    }
+}
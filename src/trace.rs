@@ -0,0 +1,634 @@
+//! Execution traces.
+//!
+//! With a `Tracer` set on the VM, every instruction it executes is recorded
+//! as a `TraceEvent`: where it was, what it was, the values of the registers
+//! it read, and the registers and heap cells it wrote. Heap cells written by
+//! native functions are not seen.
+//!
+//! Traces are written either as JSON lines, one object per instruction, or
+//! in a compact binary form. `read_trace` reads both back, and `diff` finds
+//! where two runs parted ways.
+//!
+//! The binary form starts with the magic number "BTRC" and a 2 byte big
+//! endian version, followed by the events back to back. Counts, addresses
+//! and unsigned values are LEB128 and signed ones zigzag LEB128, as in
+//! `encoding`; each `Val` and operand is preceded by a tag byte.
+
+use std::error;
+use std::fmt;
+use std::io::{self, Write};
+
+use nom::types::CompleteStr;
+use serde_json::{json, Map, Value};
+
+use crate::decoded::DecodedInstruction;
+use crate::disassembler::Operand;
+use crate::encoding::{read_float, read_sleb, read_uleb, write_float, write_sleb, write_uleb};
+use crate::instruction::{Opcode, OperandKind};
+use crate::vm::Val;
+
+pub const MAGIC: &[u8; 4] = b"BTRC";
+pub const VERSION: u16 = 1;
+
+/// A heap cell written by an instruction
+#[derive(Debug, Clone, Copy)]
+pub struct HeapWrite {
+    pub block: u64,
+    pub offset: u64,
+    pub value: Val,
+}
+
+/// Everything one executed instruction did. Equality compares floats by
+/// their bits, so an event with a NaN in it equals itself.
+#[derive(Debug, Clone)]
+pub struct TraceEvent {
+    pub pc: usize,
+    pub opcode: Opcode,
+    pub operands: Vec<Operand>,
+    /// Values of the register operands before the instruction ran
+    pub reads: Vec<(u8, Val)>,
+    /// Registers whose value changed, with their new value
+    pub writes: Vec<(u8, Val)>,
+    pub heap_writes: Vec<HeapWrite>,
+    /// The error the instruction stopped with, if it failed
+    pub error: Option<String>,
+}
+
+impl PartialEq for HeapWrite {
+    fn eq(&self, other: &HeapWrite) -> bool {
+	self.block == other.block && self.offset == other.offset && self.value.identical(&other.value)
+    }
+}
+
+impl PartialEq for TraceEvent {
+    fn eq(&self, other: &TraceEvent) -> bool {
+	self.pc == other.pc
+	    && self.opcode == other.opcode
+	    && self.operands.len() == other.operands.len()
+	    && self.operands.iter().zip(&other.operands).all(|pair| match pair {
+		(Operand::Float(a), Operand::Float(b)) => a.to_bits() == b.to_bits(),
+		(a, b) => a == b,
+	    })
+	    && same_registers(&self.reads, &other.reads)
+	    && same_registers(&self.writes, &other.writes)
+	    && self.heap_writes == other.heap_writes
+	    && self.error == other.error
+    }
+}
+
+fn same_registers(a: &[(u8, Val)], b: &[(u8, Val)]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|((r, x), (s, y))| r == s && x.identical(y))
+}
+
+impl TraceEvent {
+    /// An event for `ins` with its operands and reads filled in from the
+    /// registers as they are before it runs
+    pub fn new(ins: &DecodedInstruction, registers: &[Val]) -> TraceEvent {
+	let mut operands = vec![];
+	let mut reads = vec![];
+	let mut regs = ins.regs.iter();
+	for kind in ins.opcode.operands() {
+	    operands.push(match kind {
+		OperandKind::Register => {
+		    let r = *regs.next().unwrap();
+		    if !reads.iter().any(|(read, _)| *read == r) {
+			reads.push((r, registers[r as usize]));
+		    }
+		    Operand::Register(r)
+		},
+		OperandKind::Int => Operand::Int(ins.int()),
+		OperandKind::UInt => Operand::UInt(ins.uint()),
+		OperandKind::Float => Operand::Float(ins.float()),
+	    });
+	}
+	TraceEvent {
+	    pc: ins.address,
+	    opcode: ins.opcode,
+	    operands,
+	    reads,
+	    writes: vec![],
+	    heap_writes: vec![],
+	    error: None,
+	}
+    }
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	write!(f, "{:04}: {}", self.pc, self.opcode.mnemonic())?;
+	for operand in &self.operands {
+	    write!(f, " {}", operand)?;
+	}
+	for (r, v) in &self.writes {
+	    write!(f, " | r{} = {:?}", r, v)?;
+	}
+	for w in &self.heap_writes {
+	    write!(f, " | [{}][{}] = {:?}", w.block, w.offset, w.value)?;
+	}
+	if let Some(e) = &self.error {
+	    write!(f, " | error: {}", e)?;
+	}
+	Ok(())
+    }
+}
+
+/// Receives an event for every instruction the VM executes
+pub trait Tracer {
+    fn record(&mut self, event: &TraceEvent);
+
+    /// Flushes whatever has been recorded, reporting any write that failed
+    fn finish(&mut self) -> io::Result<()> {
+	Ok(())
+    }
+}
+
+/// Writes each event as a line of JSON
+pub struct JsonTracer<W: Write> {
+    out: W,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> JsonTracer<W> {
+    pub fn new(out: W) -> JsonTracer<W> {
+	JsonTracer { out, error: None }
+    }
+}
+
+impl<W: Write> Tracer for JsonTracer<W> {
+    fn record(&mut self, event: &TraceEvent) {
+	if self.error.is_none() {
+	    if let Err(e) = writeln!(self.out, "{}", event_json(event)) {
+		self.error = Some(e);
+	    }
+	}
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+	match self.error.take() {
+	    Some(e) => Err(e),
+	    None => self.out.flush(),
+	}
+    }
+}
+
+/// Writes events in the binary form described at the top of this module
+pub struct BinaryTracer<W: Write> {
+    out: W,
+    started: bool,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> BinaryTracer<W> {
+    pub fn new(out: W) -> BinaryTracer<W> {
+	BinaryTracer { out, started: false, error: None }
+    }
+
+    fn write(&mut self, event: &TraceEvent) -> io::Result<()> {
+	if !self.started {
+	    self.out.write_all(MAGIC)?;
+	    self.out.write_all(&VERSION.to_be_bytes())?;
+	    self.started = true;
+	}
+	let mut bytes = vec![];
+	encode_event(event, &mut bytes);
+	self.out.write_all(&bytes)
+    }
+}
+
+impl<W: Write> Tracer for BinaryTracer<W> {
+    fn record(&mut self, event: &TraceEvent) {
+	if self.error.is_none() {
+	    if let Err(e) = self.write(event) {
+		self.error = Some(e);
+	    }
+	}
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+	match self.error.take() {
+	    Some(e) => Err(e),
+	    None => self.out.flush(),
+	}
+    }
+}
+
+/// Why a trace could not be read
+#[derive(Debug, PartialEq, Clone)]
+pub enum TraceError {
+    UnsupportedVersion { found: u16, supported: u16 },
+    Malformed { reason: String },
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	match self {
+	    TraceError::UnsupportedVersion { found, supported } => write!(f, "trace version {} is not supported (expected {})", found, supported),
+	    TraceError::Malformed { reason } => write!(f, "malformed trace: {}", reason),
+	}
+    }
+}
+
+impl error::Error for TraceError {}
+
+fn malformed(reason: &str) -> TraceError {
+    TraceError::Malformed { reason: reason.to_string() }
+}
+
+/// Reads a trace in either form
+pub fn read_trace(bytes: &[u8]) -> Result<Vec<TraceEvent>, TraceError> {
+    if bytes.starts_with(MAGIC) {
+	return decode_binary(bytes);
+    }
+    let text = match std::str::from_utf8(bytes) {
+	Ok(text) => text,
+	Err(_) => return Err(malformed("neither a binary trace nor UTF-8")),
+    };
+    let mut events = vec![];
+    for (n, line) in text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+	let value: Value = match serde_json::from_str(line) {
+	    Ok(value) => value,
+	    Err(e) => return Err(TraceError::Malformed { reason: format!("line {}: {}", n + 1, e) }),
+	};
+	match event_from_json(&value) {
+	    Some(event) => events.push(event),
+	    None => return Err(TraceError::Malformed { reason: format!("line {} is not a trace event", n + 1) }),
+	}
+    }
+    Ok(events)
+}
+
+/// The index of the first event where two traces differ, or None if they are
+/// the same. If one trace is a prefix of the other, that is where the shorter
+/// one ends.
+pub fn diff(a: &[TraceEvent], b: &[TraceEvent]) -> Option<usize> {
+    match a.iter().zip(b).position(|(x, y)| x != y) {
+	Some(i) => Some(i),
+	None if a.len() != b.len() => Some(a.len().min(b.len())),
+	None => None,
+    }
+}
+
+fn val_json(v: Val) -> Value {
+    match v {
+	Val::Int(v) => json!({ "int": v }),
+	Val::Ptr(v) => json!({ "ptr": v }),
+	Val::Float(v) => float_json(v),
+    }
+}
+
+/// JSON has no NaN or infinities, so those are written by their bits, which
+/// also keeps the sign and payload of a NaN
+fn float_json(v: f64) -> Value {
+    if v.is_finite() {
+	json!({ "float": v })
+    } else {
+	json!({ "float_bits": v.to_bits() })
+    }
+}
+
+/// Also reads the strings older traces used for non-finite values
+fn float_from_json(v: &Value) -> Option<f64> {
+    match v {
+	Value::String(s) => s.parse().ok(),
+	_ => v.as_f64(),
+    }
+}
+
+fn operand_json(operand: &Operand) -> Value {
+    match operand {
+	Operand::Register(r) => json!({ "reg": r }),
+	Operand::Int(v) => json!({ "int": v }),
+	Operand::UInt(v) => json!({ "uint": v }),
+	Operand::Float(v) => float_json(*v),
+    }
+}
+
+fn registers_json(registers: &[(u8, Val)]) -> Value {
+    registers.iter().map(|(r, v)| json!({ "reg": r, "value": val_json(*v) })).collect()
+}
+
+fn event_json(event: &TraceEvent) -> Value {
+    let mut object = Map::new();
+    object.insert("pc".to_string(), json!(event.pc));
+    object.insert("op".to_string(), json!(event.opcode.mnemonic()));
+    object.insert("operands".to_string(), event.operands.iter().map(operand_json).collect());
+    object.insert("reads".to_string(), registers_json(&event.reads));
+    object.insert("writes".to_string(), registers_json(&event.writes));
+    let heap: Value = event.heap_writes.iter()
+	.map(|w| json!({ "block": w.block, "offset": w.offset, "value": val_json(w.value) }))
+	.collect();
+    object.insert("heap".to_string(), heap);
+    if let Some(e) = &event.error {
+	object.insert("error".to_string(), json!(e));
+    }
+    Value::Object(object)
+}
+
+/// The only key of a one entry object such as `{"int": 5}`, and its value
+fn single_entry(v: &Value) -> Option<(&str, &Value)> {
+    let object = v.as_object()?;
+    if object.len() != 1 {
+	return None;
+    }
+    object.iter().next().map(|(k, v)| (k.as_str(), v))
+}
+
+fn val_from_json(v: &Value) -> Option<Val> {
+    match single_entry(v)? {
+	("int", v) => v.as_i64().map(Val::Int),
+	("ptr", v) => v.as_u64().map(Val::Ptr),
+	("float", v) => float_from_json(v).map(Val::Float),
+	("float_bits", v) => v.as_u64().map(|bits| Val::Float(f64::from_bits(bits))),
+	_ => None,
+    }
+}
+
+fn operand_from_json(v: &Value) -> Option<Operand> {
+    match single_entry(v)? {
+	("reg", v) => Some(Operand::Register(register_from_json(v)?)),
+	("int", v) => v.as_i64().map(Operand::Int),
+	("uint", v) => v.as_u64().map(Operand::UInt),
+	("float", v) => float_from_json(v).map(Operand::Float),
+	("float_bits", v) => v.as_u64().map(|bits| Operand::Float(f64::from_bits(bits))),
+	_ => None,
+    }
+}
+
+fn register_from_json(v: &Value) -> Option<u8> {
+    v.as_u64().filter(|r| *r <= u64::from(u8::MAX)).map(|r| r as u8)
+}
+
+fn registers_from_json(v: &Value) -> Option<Vec<(u8, Val)>> {
+    v.as_array()?.iter().map(|e| Some((register_from_json(&e["reg"])?, val_from_json(&e["value"])?))).collect()
+}
+
+fn event_from_json(v: &Value) -> Option<TraceEvent> {
+    let opcode = Opcode::from(CompleteStr(v["op"].as_str()?));
+    let heap_writes = v["heap"].as_array()?.iter().map(|w| Some(HeapWrite {
+	block: w["block"].as_u64()?,
+	offset: w["offset"].as_u64()?,
+	value: val_from_json(&w["value"])?,
+    }));
+    Some(TraceEvent {
+	pc: v["pc"].as_u64()? as usize,
+	opcode,
+	operands: v["operands"].as_array()?.iter().map(operand_from_json).collect::<Option<_>>()?,
+	reads: registers_from_json(&v["reads"])?,
+	writes: registers_from_json(&v["writes"])?,
+	heap_writes: heap_writes.collect::<Option<_>>()?,
+	error: match &v["error"] {
+	    Value::Null => None,
+	    e => Some(e.as_str()?.to_string()),
+	},
+    })
+}
+
+fn encode_val(v: Val, out: &mut Vec<u8>) {
+    match v {
+	Val::Int(v) => {
+	    out.push(0);
+	    write_sleb(v, out);
+	},
+	Val::Ptr(v) => {
+	    out.push(1);
+	    write_uleb(v, out);
+	},
+	Val::Float(v) => {
+	    out.push(2);
+	    write_float(v, out);
+	},
+    }
+}
+
+fn encode_registers(registers: &[(u8, Val)], out: &mut Vec<u8>) {
+    write_uleb(registers.len() as u64, out);
+    for (r, v) in registers {
+	out.push(*r);
+	encode_val(*v, out);
+    }
+}
+
+fn encode_event(event: &TraceEvent, out: &mut Vec<u8>) {
+    write_uleb(event.pc as u64, out);
+    out.push(event.opcode as u8);
+    write_uleb(event.operands.len() as u64, out);
+    for operand in &event.operands {
+	match operand {
+	    Operand::Register(r) => out.extend_from_slice(&[0, *r]),
+	    Operand::Int(v) => {
+		out.push(1);
+		write_sleb(*v, out);
+	    },
+	    Operand::UInt(v) => {
+		out.push(2);
+		write_uleb(*v, out);
+	    },
+	    Operand::Float(v) => {
+		out.push(3);
+		write_float(*v, out);
+	    },
+	}
+    }
+    encode_registers(&event.reads, out);
+    encode_registers(&event.writes, out);
+    write_uleb(event.heap_writes.len() as u64, out);
+    for w in &event.heap_writes {
+	write_uleb(w.block, out);
+	write_uleb(w.offset, out);
+	encode_val(w.value, out);
+    }
+    match &event.error {
+	Some(e) => {
+	    out.push(1);
+	    write_uleb(e.len() as u64, out);
+	    out.extend_from_slice(e.as_bytes());
+	},
+	None => out.push(0),
+    }
+}
+
+/// A cursor over a binary trace
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Result<u8, TraceError> {
+	match self.bytes.get(self.pos) {
+	    Some(b) => {
+		self.pos += 1;
+		Ok(*b)
+	    },
+	    None => Err(malformed("truncated event")),
+	}
+    }
+
+    fn uleb(&mut self) -> Result<u64, TraceError> {
+	let (v, len) = read_uleb(self.bytes, self.pos).map_err(|_| malformed("bad LEB128 value"))?;
+	self.pos += len;
+	Ok(v)
+    }
+
+    fn sleb(&mut self) -> Result<i64, TraceError> {
+	let (v, len) = read_sleb(self.bytes, self.pos).map_err(|_| malformed("bad LEB128 value"))?;
+	self.pos += len;
+	Ok(v)
+    }
+
+    fn float(&mut self) -> Result<f64, TraceError> {
+	let (v, len) = read_float(self.bytes, self.pos).map_err(|_| malformed("truncated float"))?;
+	self.pos += len;
+	Ok(v)
+    }
+
+    fn val(&mut self) -> Result<Val, TraceError> {
+	match self.u8()? {
+	    0 => Ok(Val::Int(self.sleb()?)),
+	    1 => Ok(Val::Ptr(self.uleb()?)),
+	    2 => Ok(Val::Float(self.float()?)),
+	    tag => Err(TraceError::Malformed { reason: format!("unknown value tag {}", tag) }),
+	}
+    }
+
+    fn registers(&mut self) -> Result<Vec<(u8, Val)>, TraceError> {
+	let count = self.uleb()?;
+	let mut registers = vec![];
+	for _ in 0..count {
+	    registers.push((self.u8()?, self.val()?));
+	}
+	Ok(registers)
+    }
+
+    fn event(&mut self) -> Result<TraceEvent, TraceError> {
+	let pc = self.uleb()? as usize;
+	let opcode = Opcode::from(self.u8()?);
+	let mut operands = vec![];
+	for _ in 0..self.uleb()? {
+	    operands.push(match self.u8()? {
+		0 => Operand::Register(self.u8()?),
+		1 => Operand::Int(self.sleb()?),
+		2 => Operand::UInt(self.uleb()?),
+		3 => Operand::Float(self.float()?),
+		tag => return Err(TraceError::Malformed { reason: format!("unknown operand tag {}", tag) }),
+	    });
+	}
+	let reads = self.registers()?;
+	let writes = self.registers()?;
+	let mut heap_writes = vec![];
+	for _ in 0..self.uleb()? {
+	    heap_writes.push(HeapWrite { block: self.uleb()?, offset: self.uleb()?, value: self.val()? });
+	}
+	let error = match self.u8()? {
+	    0 => None,
+	    _ => {
+		let len = self.uleb()? as usize;
+		let text = match self.bytes.get(self.pos..self.pos.saturating_add(len)) {
+		    Some(text) => text,
+		    None => return Err(malformed("truncated error message")),
+		};
+		self.pos += len;
+		Some(String::from_utf8_lossy(text).into_owned())
+	    },
+	};
+	Ok(TraceEvent { pc, opcode, operands, reads, writes, heap_writes, error })
+    }
+}
+
+fn decode_binary(bytes: &[u8]) -> Result<Vec<TraceEvent>, TraceError> {
+    if bytes.len() < 6 {
+	return Err(malformed("truncated header"));
+    }
+    let version = u16::from_be_bytes([bytes[4], bytes[5]]);
+    if version != VERSION {
+	return Err(TraceError::UnsupportedVersion { found: version, supported: VERSION });
+    }
+    let mut reader = Reader { bytes, pos: 6 };
+    let mut events = vec![];
+    while reader.pos < bytes.len() {
+	events.push(reader.event()?);
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscall::SharedBuffer;
+
+    fn sample() -> Vec<TraceEvent> {
+	vec![
+	    TraceEvent {
+		pc: 0,
+		opcode: Opcode::LoadF,
+		operands: vec![Operand::Register(1), Operand::Float(f64::INFINITY)],
+		reads: vec![(1, Val::Float(f64::NAN))],
+		writes: vec![(1, Val::Float(f64::INFINITY))],
+		heap_writes: vec![],
+		error: None,
+	    },
+	    TraceEvent {
+		pc: 10,
+		opcode: Opcode::Write,
+		operands: vec![Operand::Register(0), Operand::Register(2), Operand::Int(-5)],
+		reads: vec![(0, Val::Ptr(3)), (2, Val::Int(1))],
+		writes: vec![],
+		heap_writes: vec![HeapWrite { block: 3, offset: 1, value: Val::Int(-5) }],
+		error: Some("offset 1 is \"out\" of bounds".to_string()),
+	    },
+	]
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+	let out = SharedBuffer::new();
+	let mut tracer = JsonTracer::new(out.clone());
+	for event in &sample() {
+	    tracer.record(event);
+	}
+	tracer.finish().unwrap();
+	let text = String::from_utf8(out.contents()).unwrap();
+	assert_eq!(text.lines().count(), 2);
+	assert!(text.lines().next().unwrap().contains("\"op\":\"loadf\""));
+	assert_eq!(read_trace(text.as_bytes()), Ok(sample()));
+	assert!(read_trace(b"{\"pc\": 1}\n").is_err());
+	// The sign and payload of a NaN survive
+	let mut events = sample();
+	events[0].operands[1] = Operand::Float(-f64::NAN);
+	events[0].writes = vec![(1, Val::Float(-f64::NAN))];
+	let out = SharedBuffer::new();
+	let mut tracer = JsonTracer::new(out.clone());
+	tracer.record(&events[0]);
+	tracer.finish().unwrap();
+	let read = read_trace(&out.contents()).unwrap();
+	assert_eq!(diff(&read, &events[..1]), None);
+	assert_eq!(val_from_json(&json!({ "float": "inf" })), Some(Val::Float(f64::INFINITY)));
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+	let out = SharedBuffer::new();
+	let mut tracer = BinaryTracer::new(out.clone());
+	for event in &sample() {
+	    tracer.record(event);
+	}
+	tracer.finish().unwrap();
+	let bytes = out.contents();
+	assert!(bytes.starts_with(MAGIC));
+	assert_eq!(read_trace(&bytes), Ok(sample()));
+	assert!(read_trace(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_diff() {
+	let a = sample();
+	let mut b = sample();
+	assert_eq!(diff(&a, &b), None);
+	b[0].reads[0].1 = Val::Float(-f64::NAN);
+	assert_eq!(diff(&a, &b), Some(0));
+	b[0].reads[0].1 = Val::Float(f64::NAN);
+	b[1].heap_writes[0].value = Val::Int(5);
+	assert_eq!(diff(&a, &b), Some(1));
+	assert_eq!(diff(&a, &a[..1]), Some(1));
+    }
+}
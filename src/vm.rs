@@ -1,13 +1,78 @@
+use crate::assembler::symbols::{SymbolTable, SymbolType};
+use crate::bytecode::{Binary, LoadError};
+use crate::decoded::{self, DecodedInstruction, DecodedProgram};
+use crate::encoding::DecodeError;
+use crate::heap::{AllocError, Heap, HeapError, DEFAULT_MAX_BLOCK_SIZE};
 use crate::instruction::Opcode;
-use std::collections::HashMap;
-
-#[derive(Debug, Eq, PartialEq)]
-pub enum CmpRes {
-    Eq,
-    Gt,
-    Lt,
-    Neq,
-    No,
+use crate::native::NativeRegistry;
+use crate::profile::Profiler;
+use crate::syscall::{Io, SyscallError};
+use crate::trace::{HeapWrite, TraceEvent, Tracer};
+use std::error;
+use std::fmt;
+use std::time::Instant;
+
+/// Condition flags, set by `Cmp`, `Cmpu` and the integer arithmetic opcodes
+/// and tested by the conditional jumps.
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+pub struct Flags {
+    /// The result was zero, or the compared values were equal
+    pub zero: bool,
+    /// The result was negative
+    pub sign: bool,
+    /// The operation carried or borrowed when treated as unsigned
+    pub carry: bool,
+    /// The operation overflowed when treated as signed
+    pub overflow: bool,
+    /// A float comparison involved NaN, so no ordering holds
+    pub unordered: bool,
+}
+
+impl Flags {
+    /// The flags for `a - b`, which is how both compares treat integers
+    fn compare(a: i64, b: i64) -> Flags {
+	let (diff, overflow) = a.overflowing_sub(b);
+	Flags {
+	    zero: diff == 0,
+	    sign: diff < 0,
+	    carry: (a as u64) < (b as u64),
+	    overflow,
+	    unordered: false,
+	}
+    }
+
+    fn compare_floats(a: f64, b: f64) -> Flags {
+	let less = a < b;
+	Flags {
+	    zero: a == b,
+	    sign: less,
+	    carry: less,
+	    overflow: false,
+	    unordered: a.is_nan() || b.is_nan(),
+	}
+    }
+
+    /// Whether the conditional jump `op` is taken. Signed jumps read the
+    /// sign and overflow flags, unsigned ones (ending in `u`) read carry.
+    pub fn condition(self, op: Opcode) -> bool {
+	let ordered = !self.unordered;
+	let less = self.sign != self.overflow;
+	match op {
+	    Opcode::Jeq | Opcode::JeqI => self.zero,
+	    Opcode::Jne | Opcode::JneI => !self.zero,
+	    Opcode::Jgt | Opcode::JgtI => ordered && !self.zero && !less,
+	    Opcode::Jlt | Opcode::JltI => ordered && less,
+	    Opcode::Jgq | Opcode::JgqI => ordered && !less,
+	    Opcode::Jlq | Opcode::JlqI => ordered && (self.zero || less),
+	    Opcode::Jgtu | Opcode::JgtuI => ordered && !self.zero && !self.carry,
+	    Opcode::Jltu | Opcode::JltuI => ordered && self.carry,
+	    Opcode::Jgqu | Opcode::JgquI => ordered && !self.carry,
+	    Opcode::Jlqu | Opcode::JlquI => ordered && (self.zero || self.carry),
+	    Opcode::Jo | Opcode::JoI => self.overflow,
+	    Opcode::Jno | Opcode::JnoI => !self.overflow,
+	    _ => false,
+	}
+    }
 }
 
 #[derive(Debug)]
@@ -25,34 +90,235 @@ impl MemBlock {
     }
 }
 
-#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+impl Default for MemBlock {
+    fn default() -> Self {
+	MemBlock::new()
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
 pub enum Val {
     Int(i64),
     Ptr(u64),
+    Float(f64),
 }
 
 impl Val {
     pub fn as_int(&self) -> i64 {
 	match self {
-	    Val::Int(v) => return *v,
-	    Val::Ptr(v) => return *v as i64,
+	    Val::Int(v) => *v,
+	    Val::Ptr(v) => *v as i64,
+	    Val::Float(v) => *v as i64,
 	}
     }
     pub fn as_uint(&self) -> u64 {
 	match self {
-	    Val::Int(v) => return *v as u64,
-	    Val::Ptr(v) => return *v,
+	    Val::Int(v) => *v as u64,
+	    Val::Ptr(v) => *v,
+	    Val::Float(v) => *v as u64,
+	}
+    }
+    pub fn as_float(&self) -> f64 {
+	match self {
+	    Val::Int(v) => *v as f64,
+	    Val::Ptr(v) => *v as f64,
+	    Val::Float(v) => *v,
+	}
+    }
+    /// Whether `self` and `other` hold the same bits. Unlike `==`, a NaN is
+    /// identical to itself and `0.0` is not identical to `-0.0`.
+    pub fn identical(&self, other: &Val) -> bool {
+	match (self, other) {
+	    (Val::Float(a), Val::Float(b)) => a.to_bits() == b.to_bits(),
+	    _ => self == other,
+	}
+    }
+}
+
+/// What `Add`, `Sub`, `Mul` and `Div` do when the result does not fit an `i64`.
+/// Whatever the mode, the overflow flag records that it happened.
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+pub enum ArithMode {
+    /// Keep the low 64 bits, as two's complement hardware does
+    #[default]
+    Wrapping,
+    /// Fault with `VmError::ArithmeticOverflow`
+    Checked,
+    /// Clamp to `i64::MIN` or `i64::MAX`
+    Saturating,
+}
+
+/// Why `VM::run_for` returned
+#[derive(Debug, PartialEq, Clone)]
+pub enum RunStatus {
+    /// The program halted or ran off the end
+    Halted,
+    /// The instruction budget ran out
+    OutOfFuel,
+    /// The program executed `yield`
+    Waiting,
+    /// The program faulted; `pc` is left at the faulting instruction
+    Error(VmError),
+}
+
+/// How deep `Call` may nest before the VM refuses to push another frame.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1024;
+
+/// Caps on the resources a program may use, for running code that is not
+/// trusted. Applied with `VM::set_limits`; the heap limits also cover blocks
+/// that syscalls allocate.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Limits {
+    /// Most cells in all live heap blocks together
+    pub max_heap_cells: usize,
+    /// Largest heap block, in cells
+    pub max_block_size: usize,
+    /// Most heap blocks live at once
+    pub max_blocks: usize,
+    pub max_call_depth: usize,
+}
+
+impl Default for Limits {
+    /// Heap blocks of up to `DEFAULT_MAX_BLOCK_SIZE` cells but no other heap
+    /// limits, and calls nested `DEFAULT_MAX_CALL_DEPTH` deep
+    fn default() -> Self {
+	Limits {
+	    max_heap_cells: usize::MAX,
+	    max_block_size: DEFAULT_MAX_BLOCK_SIZE,
+	    max_blocks: usize::MAX,
+	    max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+	}
+    }
+}
+
+/// A saved call frame, pushed by `Call` and popped by `Ret`.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Frame {
+    /// Where execution resumes once the callee returns
+    pub return_address: usize,
+    /// The address that was called, handy when inspecting the stack
+    pub target: usize,
+}
+
+/// Everything that can go wrong while executing bytecode. Each variant carries
+/// the `pc` of the instruction that faulted.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum VmError {
+    IllegalOpcode { pc: usize, opcode: u8 },
+    DivisionByZero { pc: usize },
+    HeapOutOfBounds { pc: usize, block: u64, offset: u64 },
+    UnknownBlock { pc: usize, block: u64 },
+    UseAfterFree { pc: usize, block: u64 },
+    DoubleFree { pc: usize, block: u64 },
+    BadAllocSize { pc: usize, size: i64 },
+    /// An `Alloc` the heap's limits did not allow, even after a collection
+    AllocLimit { pc: usize, size: i64, error: AllocError },
+    ArithmeticOverflow { pc: usize },
+    TruncatedOperand { pc: usize },
+    MalformedOperand { pc: usize },
+    BadJumpTarget { pc: usize, target: i64 },
+    CallStackOverflow { pc: usize, depth: usize },
+    CallStackUnderflow { pc: usize },
+    UnknownNative { pc: usize, index: u64 },
+    NativeFailed { pc: usize, message: String },
+    UnknownSyscall { pc: usize, number: u64 },
+    SyscallFailed { pc: usize, number: u64, message: String },
+}
+
+impl VmError {
+    /// The address of the instruction that caused the error
+    pub fn pc(&self) -> usize {
+	match *self {
+	    VmError::IllegalOpcode { pc, .. } => pc,
+	    VmError::DivisionByZero { pc } => pc,
+	    VmError::HeapOutOfBounds { pc, .. } => pc,
+	    VmError::UnknownBlock { pc, .. } => pc,
+	    VmError::UseAfterFree { pc, .. } => pc,
+	    VmError::DoubleFree { pc, .. } => pc,
+	    VmError::BadAllocSize { pc, .. } => pc,
+	    VmError::AllocLimit { pc, .. } => pc,
+	    VmError::ArithmeticOverflow { pc } => pc,
+	    VmError::TruncatedOperand { pc } => pc,
+	    VmError::MalformedOperand { pc } => pc,
+	    VmError::BadJumpTarget { pc, .. } => pc,
+	    VmError::CallStackOverflow { pc, .. } => pc,
+	    VmError::CallStackUnderflow { pc } => pc,
+	    VmError::UnknownNative { pc, .. } => pc,
+	    VmError::NativeFailed { pc, .. } => pc,
+	    VmError::UnknownSyscall { pc, .. } => pc,
+	    VmError::SyscallFailed { pc, .. } => pc,
+	}
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	match self {
+	    VmError::IllegalOpcode { pc, opcode } => write!(f, "illegal opcode {} at pc {}", opcode, pc),
+	    VmError::DivisionByZero { pc } => write!(f, "division by zero at pc {}", pc),
+	    VmError::HeapOutOfBounds { pc, block, offset } => write!(f, "offset {} is out of bounds for block {} at pc {}", offset, block, pc),
+	    VmError::UnknownBlock { pc, block } => write!(f, "unknown heap block {} at pc {}", block, pc),
+	    VmError::UseAfterFree { pc, block } => write!(f, "heap block {} used after being freed at pc {}", block, pc),
+	    VmError::DoubleFree { pc, block } => write!(f, "heap block {} freed twice at pc {}", block, pc),
+	    VmError::BadAllocSize { pc, size } => write!(f, "cannot allocate a block of {} cells at pc {}", size, pc),
+	    VmError::AllocLimit { pc, size, error } => write!(f, "cannot allocate a block of {} cells at pc {}: {}", size, pc, error),
+	    VmError::ArithmeticOverflow { pc } => write!(f, "arithmetic overflow at pc {}", pc),
+	    VmError::TruncatedOperand { pc } => write!(f, "instruction at pc {} is missing operand bytes", pc),
+	    VmError::MalformedOperand { pc } => write!(f, "instruction at pc {} has an overlong immediate", pc),
+	    VmError::BadJumpTarget { pc, target } => write!(f, "jump to {} from pc {} leaves the program", target, pc),
+	    VmError::CallStackOverflow { pc, depth } => write!(f, "call stack overflow (depth {}) at pc {}", depth, pc),
+	    VmError::CallStackUnderflow { pc } => write!(f, "ret with an empty call stack at pc {}", pc),
+	    VmError::UnknownNative { pc, index } => write!(f, "extern {} is not linked to a native function at pc {}", index, pc),
+	    VmError::NativeFailed { pc, message } => write!(f, "native function failed at pc {}: {}", pc, message),
+	    VmError::UnknownSyscall { pc, number } => write!(f, "unknown syscall {} at pc {}", number, pc),
+	    VmError::SyscallFailed { pc, number, message } => write!(f, "syscall {} failed at pc {}: {}", number, pc, message),
 	}
     }
 }
 
+impl error::Error for VmError {}
+
+/// A register's value as a shift amount, clamped so it still fits a `u32`
+fn shift_amount(v: Val) -> u32 {
+    v.as_uint().min(u64::from(u32::MAX)) as u32
+}
+
 pub struct VM {
     pub registers: [Val; 256],
     pc: usize,
+    /// Start of the instruction currently being executed, reported in errors
+    ins_start: usize,
     pub program: Vec<u8>,
-    remainder: u64,
-    pub heap: HashMap<u64, MemBlock>,
-    equal_flag: CmpRes,
+    /// Remainder of the last `Div`, read with `Getrem`
+    remainder: i64,
+    pub heap: Heap,
+    pub flags: Flags,
+    pub arith_mode: ArithMode,
+    pub call_stack: Vec<Frame>,
+    pub max_call_depth: usize,
+    /// Symbols from the loaded binary, if it had any
+    pub symbols: SymbolTable,
+    pub natives: NativeRegistry,
+    /// Index into `natives` for each extern, built by `link_natives`
+    native_links: Vec<Option<usize>>,
+    /// Where the `Syscall` opcode reads and writes. `VM::new` gives programs
+    /// the process's stdio and unrestricted file access; replace it with
+    /// `Io::disabled()` before running untrusted code.
+    pub io: Io,
+    /// Receives an event for every instruction executed, see `crate::trace`
+    pub tracer: Option<Box<dyn Tracer>>,
+    /// Heap writes made by the instruction being traced
+    heap_writes: Vec<HeapWrite>,
+    /// Counts and times every instruction executed, see `crate::profile`
+    pub profiler: Option<Profiler>,
+    /// Set by `Yield` for `run_for` to notice
+    yielded: bool,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+	VM::new()
+    }
 }
 
 impl VM {
@@ -60,225 +326,647 @@ impl VM {
         VM {
             registers: [Val::Int(0); 256],
             pc: 0,
+	    ins_start: 0,
             program: vec![],
 	    remainder: 0,
-	    equal_flag: CmpRes::No,
-	    heap: HashMap::new(),
+	    flags: Flags::default(),
+	    arith_mode: ArithMode::default(),
+	    heap: Heap::new(),
+	    call_stack: Vec::new(),
+	    max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+	    symbols: SymbolTable::new(),
+	    natives: NativeRegistry::new(),
+	    native_links: vec![],
+	    io: Io::new(),
+	    tracer: None,
+	    heap_writes: vec![],
+	    profiler: None,
+	    yielded: false,
         }
     }
 
-    fn next_8_bits(&mut self) -> u8 {
-	let result = self.program[self.pc];
-	self.pc += 1;
-	result
+    /// Address of the next instruction to execute
+    pub fn pc(&self) -> usize {
+	self.pc
     }
 
-    fn get_int(&mut self) -> i64 {
-	let result = ((self.program[self.pc] as u64) << 56) | ((self.program[self.pc + 1] as u64) << 48) | ((self.program[self.pc + 2] as u64) << 40) | ((self.program[self.pc + 3] as u64) << 32) | ((self.program[self.pc + 4] as u64) << 24) | ((self.program[self.pc + 5] as u64) << 16) | ((self.program[self.pc + 6] as u64) << 8) | self.program[self.pc + 7] as u64;
-	self.pc += 8;
-	let sign = (result & (0b1 << 63)) >> 63;
-	let res = (result & (0b0111111111111111111111111111111111111111111111111111111111111111)) as i64;
-	if sign > 0 {
-	    return -res;
-	} else {
-	    res
+    pub fn limits(&self) -> Limits {
+	Limits {
+	    max_heap_cells: self.heap.max_cells,
+	    max_block_size: self.heap.max_block_size,
+	    max_blocks: self.heap.max_blocks,
+	    max_call_depth: self.max_call_depth,
 	}
     }
-    
-    fn get_uint(&mut self) -> u64 {
-	let result = ((self.program[self.pc] as u64) << 56) | ((self.program[self.pc + 1] as u64) << 48) | ((self.program[self.pc + 2] as u64) << 40) | ((self.program[self.pc + 3] as u64) << 32) | ((self.program[self.pc + 4] as u64) << 24) | ((self.program[self.pc + 5] as u64) << 16) | ((self.program[self.pc + 6] as u64) << 8) | self.program[self.pc + 7] as u64;
-	self.pc += 8;
-	result
+
+    /// Sets every limit at once. Blocks already on the heap are kept even if
+    /// they break the new limits.
+    pub fn set_limits(&mut self, limits: Limits) {
+	self.heap.max_cells = limits.max_heap_cells;
+	self.heap.max_block_size = limits.max_block_size;
+	self.heap.max_blocks = limits.max_blocks;
+	self.max_call_depth = limits.max_call_depth;
+    }
+
+    /// Replaces the current program with a bytecode file produced by
+    /// `Assembler::assemble_binary`, after checking its header. Data blocks are
+    /// loaded into the heap and execution is set to begin at the entry point.
+    /// Registers, flags and the remainder start out cleared, and files the
+    /// previous program left open are closed.
+    pub fn load_bytecode(&mut self, bytes: &[u8]) -> Result<(), LoadError> {
+	let binary = Binary::parse(bytes)?;
+	self.program = binary.code;
+	self.load_data(&binary.data);
+	self.symbols = binary.symbols;
+	self.link_natives();
+	self.call_stack.clear();
+	self.registers = [Val::Int(0); 256];
+	self.flags = Flags::default();
+	self.remainder = 0;
+	self.yielded = false;
+	self.io.close_all();
+	self.pc = binary.entry as usize;
+	Ok(())
+    }
+
+    /// Registers a host function for programs to call, see `crate::native`
+    pub fn register_native<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+	F: FnMut(&[Val], &mut Heap) -> Result<Val, String> + 'static,
+    {
+	self.natives.register(name, arity, function);
+	self.link_natives();
+    }
+
+    /// Points each extern in `symbols` at the native function of the same
+    /// name. Done by `load_bytecode` and `register_native`; call it after
+    /// setting `symbols` by hand.
+    pub fn link_natives(&mut self) {
+	self.native_links.clear();
+	let count = self.symbols.symbols().len();
+	for symbol in self.symbols.symbols().iter().filter(|s| s.symbol_type == SymbolType::Extern) {
+	    let index = symbol.offset as usize;
+	    // Externs are numbered from 0, so this can only be a corrupt binary
+	    if index >= count {
+		continue;
+	    }
+	    if self.native_links.len() <= index {
+		self.native_links.resize(index + 1, None);
+	    }
+	    self.native_links[index] = self.natives.index_of(&symbol.name);
+	}
+    }
+
+    /// Loads the blocks produced by the assembler's data section into a fresh
+    /// heap, so that each block's id is its index.
+    pub fn load_data(&mut self, blocks: &[Vec<Val>]) {
+	self.heap.clear();
+	for cells in blocks {
+	    self.heap.alloc_with(cells.clone());
+	}
+	self.heap.pin_all();
+    }
+
+    /// Runs a garbage collection using the registers as roots and returns the
+    /// number of blocks freed
+    pub fn collect_garbage(&mut self) -> usize {
+	let roots = self.registers.iter().filter_map(|r| match *r {
+	    Val::Ptr(p) => Some(p),
+	    _ => None,
+	});
+	self.heap.collect(roots)
+    }
+
+    /// The error for an instruction at `ins_start` that could not be decoded
+    fn decode_error(&self, e: DecodeError) -> VmError {
+	match e {
+	    DecodeError::Truncated => VmError::TruncatedOperand { pc: self.ins_start },
+	    DecodeError::Overlong => VmError::MalformedOperand { pc: self.ins_start },
+	}
     }
 
-    pub fn run(&mut self) {
+    /// Moves `pc` to `target`, which may be anywhere in the program or one past
+    /// its end (which simply ends execution).
+    fn jump(&mut self, target: i64) -> Result<(), VmError> {
+	if target < 0 || target as usize > self.program.len() {
+	    return Err(VmError::BadJumpTarget { pc: self.ins_start, target });
+	}
+	self.pc = target as usize;
+	Ok(())
+    }
+
+    /// Runs the program until it halts, runs off the end, or faults.
+    pub fn run(&mut self) -> Result<(), VmError> {
 	let mut is_done = false;
 	while !is_done {
-            is_done = self.execute_instruction();
+	    is_done = self.execute_instruction()?;
+	}
+	Ok(())
+    }
+
+    /// Executes at most `fuel` instructions. After `OutOfFuel` or `Waiting`
+    /// another call carries on exactly where this one stopped.
+    ///
+    /// `yield` ends the call early so a program can hand back control
+    /// before its budget is spent; `run` and `run_decoded` ignore it.
+    pub fn run_for(&mut self, fuel: u64) -> RunStatus {
+	self.yielded = false;
+	for _ in 0..fuel {
+	    match self.execute_instruction() {
+		Ok(true) => return RunStatus::Halted,
+		Ok(false) => {},
+		Err(e) => return RunStatus::Error(e),
+	    }
+	    if std::mem::take(&mut self.yielded) {
+		return RunStatus::Waiting;
+	    }
 	}
+	if self.pc >= self.program.len() {
+	    return RunStatus::Halted;
+	}
+	RunStatus::OutOfFuel
     }
 
-    pub fn execute_instruction(&mut self) -> bool {
-	
+    /// Runs the program like `run`, but decodes all of it up front instead of
+    /// decoding each instruction as it is reached. Worth it for programs that
+    /// loop; see `benches/dispatch.rs`.
+    pub fn run_decoded(&mut self) -> Result<(), VmError> {
+	let decoded = DecodedProgram::new(&self.program);
+	let mut index = decoded.index_of(self.pc);
+	loop {
+	    index = match index {
+		Some(i) => {
+		    if self.execute(&decoded.instructions[i])? {
+			return Ok(());
+		    }
+		    decoded.successor(i, self.pc)
+		},
+		// The end of the program, the middle of an instruction or
+		// something undecodable: take the slow path, which knows what
+		// to do with all of them
+		None => {
+		    if self.execute_instruction()? {
+			return Ok(());
+		    }
+		    decoded.index_of(self.pc)
+		},
+	    };
+	}
+    }
+
+    /// Executes a single instruction, returning `Ok(true)` once the program is
+    /// done. On error `pc` is left pointing at the faulting instruction.
+    pub fn execute_instruction(&mut self) -> Result<bool, VmError> {
         if self.pc >= self.program.len() {
-            return true;
-        }
-        match self.decode_opcode() {
+	    return Ok(true);
+	}
+	self.ins_start = self.pc;
+	match decoded::decode_instruction(&self.program, self.pc) {
+	    Ok(ins) => self.execute(&ins),
+	    Err(e) => Err(self.decode_error(e)),
+	}
+    }
+
+    fn execute(&mut self, ins: &DecodedInstruction) -> Result<bool, VmError> {
+	if self.profiler.is_some() {
+	    return self.execute_profiled(ins);
+	}
+	if self.tracer.is_some() {
+	    return self.execute_traced(ins);
+	}
+	self.execute_untraced(ins)
+    }
+
+    /// `execute` with a profiler set: times the instruction, tracing it too if
+    /// there is a tracer
+    fn execute_profiled(&mut self, ins: &DecodedInstruction) -> Result<bool, VmError> {
+	let start = Instant::now();
+	let result = if self.tracer.is_some() {
+	    self.execute_traced(ins)
+	} else {
+	    self.execute_untraced(ins)
+	};
+	let time = start.elapsed();
+	if let Some(profiler) = &mut self.profiler {
+	    profiler.record(ins, time, &self.call_stack);
+	}
+	result
+    }
+
+    fn execute_untraced(&mut self, ins: &DecodedInstruction) -> Result<bool, VmError> {
+	self.ins_start = ins.address;
+	self.pc = ins.next;
+	let result = self.execute_opcode(ins);
+	if result.is_err() {
+	    self.pc = self.ins_start;
+	}
+	result
+    }
+
+    /// `execute` with a tracer set: runs the instruction and records what it
+    /// did
+    fn execute_traced(&mut self, ins: &DecodedInstruction) -> Result<bool, VmError> {
+	let mut event = TraceEvent::new(ins, &self.registers);
+	let before = self.registers;
+	let result = self.execute_untraced(ins);
+	event.writes = (0..=255u8)
+	    .filter(|r| !self.registers[*r as usize].identical(&before[*r as usize]))
+	    .map(|r| (r, self.registers[r as usize]))
+	    .collect();
+	event.heap_writes = std::mem::take(&mut self.heap_writes);
+	event.error = result.as_ref().err().map(|e| e.to_string());
+	if let Some(tracer) = &mut self.tracer {
+	    tracer.record(&event);
+	}
+	result
+    }
+
+    fn execute_opcode(&mut self, ins: &DecodedInstruction) -> Result<bool, VmError> {
+	let opcode = ins.opcode;
+	match opcode {
             Opcode::Hlt => {
-                println!("hlt encountered");
-		return true;
+		return Ok(true);
             },
 	    Opcode::Load => {
-		let register = self.next_8_bits() as usize; // We cast to usize so we can use it as an index into the array
-		let number = self.get_int();
+		let register = ins.regs[0] as usize; // We cast to usize so we can use it as an index into the array
+		let number = ins.int();
 		self.registers[register] = Val::Int(number); // Our registers are i32s, so we need to cast it. We'll cover that later.
 	    },
 	    Opcode::Add => {
-		let r1 = self.registers[self.next_8_bits() as usize];
-		let r2 = self.registers[self.next_8_bits() as usize];
-		self.registers[self.next_8_bits() as usize] = Val::Int(r1.as_int() + r2.as_int());
+		let r1 = self.registers[ins.regs[0] as usize];
+		let r2 = self.registers[ins.regs[1] as usize];
+		let v = self.add(r1, r2)?;
+		self.registers[ins.regs[2] as usize] = Val::Int(v);
 	    },
 	    Opcode::Sub => {
-		let r1 = self.registers[self.next_8_bits() as usize];
-		let r2 = self.registers[self.next_8_bits() as usize];
-		self.registers[self.next_8_bits() as usize] = Val::Int(r1.as_int() - r2.as_int());
+		let r1 = self.registers[ins.regs[0] as usize];
+		let r2 = self.registers[ins.regs[1] as usize];
+		let v = self.sub(r1, r2)?;
+		self.registers[ins.regs[2] as usize] = Val::Int(v);
 	    },
 	    Opcode::Mul => {
-		let r1 = self.registers[self.next_8_bits() as usize];
-		let r2 = self.registers[self.next_8_bits() as usize];
-		self.registers[self.next_8_bits() as usize] = Val::Int(r1.as_int() * r2.as_int());
+		let r1 = self.registers[ins.regs[0] as usize];
+		let r2 = self.registers[ins.regs[1] as usize];
+		let v = self.mul(r1, r2)?;
+		self.registers[ins.regs[2] as usize] = Val::Int(v);
 	    },
 	    Opcode::Div => {
-		let r1 = self.registers[self.next_8_bits() as usize];
-		let r2 = self.registers[self.next_8_bits() as usize];
-		self.registers[self.next_8_bits() as usize] = Val::Int(r1.as_int() / r2.as_int());
-		self.remainder = (r1.as_int() % r1.as_int()) as u64;
+		// Truncates toward zero, so the remainder takes the sign of the
+		// dividend. `i64::MIN / -1` overflows, following `arith_mode`,
+		// and leaves a remainder of 0.
+		let r1 = self.registers[ins.regs[0] as usize];
+		let r2 = self.registers[ins.regs[1] as usize];
+		if r2.as_int() == 0 {
+		    return Err(VmError::DivisionByZero { pc: self.ins_start });
+		}
+		let v = self.arith_result(r1.as_int().overflowing_div(r2.as_int()), false, r1.as_int().saturating_div(r2.as_int()))?;
+		self.registers[ins.regs[2] as usize] = Val::Int(v);
+		self.remainder = r1.as_int().wrapping_rem(r2.as_int());
+	    },
+	    Opcode::Mod => {
+		// Floored modulo: the result takes the sign of the divisor
+		let r1 = self.registers[ins.regs[0] as usize];
+		let r2 = self.registers[ins.regs[1] as usize];
+		let (a, b) = (r1.as_int(), r2.as_int());
+		if b == 0 {
+		    return Err(VmError::DivisionByZero { pc: self.ins_start });
+		}
+		let r = a.wrapping_rem(b);
+		let m = if r != 0 && (r < 0) != (b < 0) { r + b } else { r };
+		self.registers[ins.regs[2] as usize] = Val::Int(m);
+	    },
+	    Opcode::Getrem => {
+		let target = ins.regs[0] as usize;
+		self.registers[target] = Val::Int(self.remainder);
+	    },
+	    Opcode::LoadF => {
+		let register = ins.regs[0] as usize;
+		self.registers[register] = Val::Float(ins.float());
+	    },
+	    Opcode::AddF => {
+		let r1 = self.registers[ins.regs[0] as usize];
+		let r2 = self.registers[ins.regs[1] as usize];
+		self.registers[ins.regs[2] as usize] = Val::Float(r1.as_float() + r2.as_float());
+	    },
+	    Opcode::SubF => {
+		let r1 = self.registers[ins.regs[0] as usize];
+		let r2 = self.registers[ins.regs[1] as usize];
+		self.registers[ins.regs[2] as usize] = Val::Float(r1.as_float() - r2.as_float());
+	    },
+	    Opcode::MulF => {
+		let r1 = self.registers[ins.regs[0] as usize];
+		let r2 = self.registers[ins.regs[1] as usize];
+		self.registers[ins.regs[2] as usize] = Val::Float(r1.as_float() * r2.as_float());
+	    },
+	    Opcode::DivF => {
+		// Follows IEEE 754, so dividing by zero gives an infinity or NaN
+		let r1 = self.registers[ins.regs[0] as usize];
+		let r2 = self.registers[ins.regs[1] as usize];
+		self.registers[ins.regs[2] as usize] = Val::Float(r1.as_float() / r2.as_float());
+	    },
+	    Opcode::Itof => {
+		let target = ins.regs[0] as usize;
+		let v = self.registers[ins.regs[1] as usize];
+		self.registers[target] = Val::Float(v.as_int() as f64);
+	    },
+	    Opcode::Ftoi => {
+		// Rounds toward zero, saturating at the i64 limits; NaN becomes 0
+		let target = ins.regs[0] as usize;
+		let v = self.registers[ins.regs[1] as usize];
+		self.registers[target] = Val::Int(v.as_float() as i64);
+	    },
+	    Opcode::And => {
+		let r1 = self.registers[ins.regs[0] as usize];
+		let r2 = self.registers[ins.regs[1] as usize];
+		self.registers[ins.regs[2] as usize] = Val::Int(r1.as_int() & r2.as_int());
+	    },
+	    Opcode::Or => {
+		let r1 = self.registers[ins.regs[0] as usize];
+		let r2 = self.registers[ins.regs[1] as usize];
+		self.registers[ins.regs[2] as usize] = Val::Int(r1.as_int() | r2.as_int());
+	    },
+	    Opcode::Xor => {
+		let r1 = self.registers[ins.regs[0] as usize];
+		let r2 = self.registers[ins.regs[1] as usize];
+		self.registers[ins.regs[2] as usize] = Val::Int(r1.as_int() ^ r2.as_int());
+	    },
+	    Opcode::Not => {
+		let target = ins.regs[0] as usize;
+		let v = self.registers[ins.regs[1] as usize];
+		self.registers[target] = Val::Int(!v.as_int());
+	    },
+	    // Shift amounts are unsigned; shifting out every bit (64 or more)
+	    // leaves 0, or all sign bits for `Sar`
+	    Opcode::Shl => {
+		let r1 = self.registers[ins.regs[0] as usize];
+		let r2 = self.registers[ins.regs[1] as usize];
+		let v = r1.as_uint().checked_shl(shift_amount(r2)).unwrap_or(0);
+		self.registers[ins.regs[2] as usize] = Val::Int(v as i64);
+	    },
+	    Opcode::Shr => {
+		let r1 = self.registers[ins.regs[0] as usize];
+		let r2 = self.registers[ins.regs[1] as usize];
+		let v = r1.as_uint().checked_shr(shift_amount(r2)).unwrap_or(0);
+		self.registers[ins.regs[2] as usize] = Val::Int(v as i64);
+	    },
+	    Opcode::Sar => {
+		let r1 = self.registers[ins.regs[0] as usize];
+		let r2 = self.registers[ins.regs[1] as usize];
+		let v = r1.as_int() >> shift_amount(r2).min(63);
+		self.registers[ins.regs[2] as usize] = Val::Int(v);
 	    },
 	    Opcode::Jmp => {
-		let t = self.registers[self.next_8_bits() as usize];
-		self.pc = t.as_uint() as usize;
+		let t = self.registers[ins.regs[0] as usize];
+		self.jump(t.as_int())?;
 	    },
 	    Opcode::Jmpf => {
-		let v = self.registers[self.next_8_bits() as usize];
-		self.pc += v.as_uint() as usize;
+		let v = self.registers[ins.regs[0] as usize];
+		self.jump((self.pc as i64).wrapping_add(v.as_int()))?;
 	    },
 	    Opcode::Jmpb => {
-		let v = self.registers[self.next_8_bits() as usize];
-		self.pc -= v.as_uint() as usize;
+		let v = self.registers[ins.regs[0] as usize];
+		self.jump((self.pc as i64).wrapping_sub(v.as_int()))?;
 	    },
 	    Opcode::Cmp => {
-		let r1 = self.registers[self.next_8_bits() as usize];
-		let r2 = self.registers[self.next_8_bits() as usize];
-		if r1.as_int() == r2.as_int() {
-		    self.equal_flag = CmpRes::Eq;
-		} else if r1.as_int() > r2.as_int() {
-		    self.equal_flag = CmpRes::Gt;
-		} else if r1.as_int() < r2.as_int() {
-		    self.equal_flag = CmpRes::Lt;
+		let r1 = self.registers[ins.regs[0] as usize];
+		let r2 = self.registers[ins.regs[1] as usize];
+		self.flags = if let (Val::Float(_), _) | (_, Val::Float(_)) = (r1, r2) {
+		    Flags::compare_floats(r1.as_float(), r2.as_float())
 		} else {
-		    self.equal_flag = CmpRes::Neq;
-		}
+		    Flags::compare(r1.as_int(), r2.as_int())
+		};
 	    },
-	    Opcode::Jeq => {
-		let t = self.registers[self.next_8_bits() as usize];
-		if self.equal_flag == CmpRes::Eq {
-		    self.pc = t.as_uint() as usize;
-		}
+	    Opcode::Cmpu => {
+		// Always compares the raw integer values, even for floats
+		let r1 = self.registers[ins.regs[0] as usize];
+		let r2 = self.registers[ins.regs[1] as usize];
+		self.flags = Flags::compare(r1.as_uint() as i64, r2.as_uint() as i64);
 	    },
-	    Opcode::Jne => {
-		let t = self.registers[self.next_8_bits() as usize];
-		if self.equal_flag == CmpRes::Neq {
-		    self.pc = t.as_uint() as usize;
+	    Opcode::Jeq | Opcode::Jne | Opcode::Jgt | Opcode::Jlt | Opcode::Jgq | Opcode::Jlq |
+	    Opcode::Jgtu | Opcode::Jltu | Opcode::Jgqu | Opcode::Jlqu | Opcode::Jo | Opcode::Jno => {
+		let t = self.registers[ins.regs[0] as usize];
+		if self.flags.condition(opcode) {
+		    self.jump(t.as_int())?;
 		}
 	    },
-	    Opcode::Jgt => {
-		let t = self.registers[self.next_8_bits() as usize];
-		if self.equal_flag == CmpRes::Gt {
-		    self.pc = t.as_uint() as usize;
-		}
+	    Opcode::Write => {
+		let b_addr = ins.regs[0];
+		let block = self.registers[b_addr as usize];
+		let offset = self.registers[ins.regs[1] as usize];
+		let v = ins.int();
+		self.registers[b_addr as usize] = Val::Ptr(block.as_uint());
+		self.write_cell(block.as_uint(), offset.as_uint(), Val::Int(v))?;
 	    },
-	    Opcode::Jlt => {
-		let t = self.registers[self.next_8_bits() as usize];
-		if self.equal_flag == CmpRes::Lt {
-		    self.pc = t.as_uint() as usize;
+	    Opcode::WritePtr => {
+		let b_addr = ins.regs[0];
+		let block = self.registers[b_addr as usize];
+		let offset = self.registers[ins.regs[1] as usize];
+		let v = ins.uint();
+		self.registers[b_addr as usize] = Val::Ptr(block.as_uint());
+		self.write_cell(block.as_uint(), offset.as_uint(), Val::Ptr(v))?;
+	    },
+	    Opcode::Loadptr => {
+		let register = ins.regs[0] as usize; // We cast to usize so we can use it as an index into the array
+		let number = ins.uint();
+		self.registers[register] = Val::Ptr(number); // Our registers are i32s, so we need to cast it. We'll cover that later.
+	    },
+	    Opcode::Deref => {
+		let b_addr = ins.regs[0];
+		let block = self.registers[b_addr as usize];
+		let offset = self.registers[ins.regs[1] as usize];
+		let target = ins.regs[2] as usize;
+		self.registers[b_addr as usize] = Val::Ptr(block.as_uint());
+		self.registers[target] = self.read_cell(block.as_uint(), offset.as_uint())?;
+	    },
+	    Opcode::Alloc => {
+		let target = ins.regs[0] as usize;
+		let size = self.registers[ins.regs[1] as usize].as_int();
+		if size < 0 {
+		    return Err(VmError::BadAllocSize { pc: self.ins_start, size });
 		}
+		let cells = size as usize;
+		let result = self.heap.make_room(cells, &self.registers).and_then(|_| self.heap.try_alloc(cells));
+		let block = match result {
+		    Ok(block) => block,
+		    Err(AllocError::OutOfMemory) => return Err(VmError::BadAllocSize { pc: self.ins_start, size }),
+		    Err(error) => return Err(VmError::AllocLimit { pc: self.ins_start, size, error }),
+		};
+		self.registers[target] = Val::Ptr(block);
 	    },
-	    Opcode::Jgq => {
-		let t = self.registers[self.next_8_bits() as usize];
-		if self.equal_flag == CmpRes::Gt || self.equal_flag == CmpRes::Eq {
-		    self.pc = t.as_uint() as usize;
+	    Opcode::Free => {
+		let block = self.registers[ins.regs[0] as usize].as_uint();
+		if let Err(e) = self.heap.free(block) {
+		    return Err(self.heap_error(e, block));
 		}
 	    },
-	    Opcode::Jlq => {
-		let t = self.registers[self.next_8_bits() as usize];
-		if self.equal_flag == CmpRes::Lt || self.equal_flag == CmpRes::Eq {
-		    self.pc = t.as_uint() as usize;
+	    Opcode::Call => {
+		let t = self.registers[ins.regs[0] as usize];
+		self.call(t.as_int())?;
+	    },
+	    Opcode::CallI => {
+		let t = ins.uint();
+		self.call(t as i64)?;
+	    },
+	    Opcode::CallNative => {
+		let index = ins.uint();
+		let native = match self.native_links.get(index as usize) {
+		    Some(Some(native)) => *native,
+		    _ => return Err(VmError::UnknownNative { pc: self.ins_start, index }),
+		};
+		let function = self.natives.get_mut(native).unwrap();
+		match function.call(&self.registers[..function.arity], &mut self.heap) {
+		    Ok(v) => self.registers[0] = v,
+		    Err(message) => return Err(VmError::NativeFailed { pc: self.ins_start, message }),
 		}
 	    },
-	    Opcode::Write => {
-		let b_addr = self.next_8_bits();
-		let block = self.registers[b_addr as usize];
-		let offset = self.registers[self.next_8_bits() as usize];
-		let v = self.get_int();
-		self.registers[b_addr as usize] = Val::Ptr(block.as_uint() as u64);
-		if self.heap.contains_key(&(block.as_uint() as u64)) {
-		    if let Some(k) = self.heap.get_mut(&(block.as_uint() as u64)) {
-			while k.data.len() <= offset.as_uint() as usize {
-			    k.data.push(Val::Int(0));
-			}
-			k.data[offset.as_uint() as usize] = Val::Int(v);
-			k.length = k.data.len() as u64;
-		    }
-		} else {
-		    self.heap.insert(block.as_uint() as u64, MemBlock::new());
-		    
-		    if let Some(k) = self.heap.get_mut(&(block.as_uint() as u64)) {
-			while k.data.len() <= offset.as_uint() as usize {
-			    k.data.push(Val::Int(0));
-			}
-			k.data[offset.as_uint() as usize] = Val::Int(v);
-			k.length = k.data.len() as u64;
-		    }
+	    Opcode::Syscall => {
+		let number = ins.uint();
+		match self.io.syscall(number, &mut self.registers, &mut self.heap) {
+		    Ok(()) => {},
+		    Err(SyscallError::Unknown) => return Err(VmError::UnknownSyscall { pc: self.ins_start, number }),
+		    Err(SyscallError::Failed(message)) => return Err(VmError::SyscallFailed { pc: self.ins_start, number, message }),
 		}
 	    },
-	    Opcode::WritePtr => {
-		let b_addr = self.next_8_bits();
-		let block = self.registers[b_addr as usize];
-		let offset = self.registers[self.next_8_bits() as usize];
-		let v = self.get_uint();
-		self.registers[b_addr as usize] = Val::Ptr(block.as_uint() as u64);
-		if self.heap.contains_key(&(block.as_uint() as u64)) {
-		    if let Some(k) = self.heap.get_mut(&(block.as_uint() as u64)) {
-			while k.data.len() <= offset.as_uint() as usize {
-			    k.data.push(Val::Int(0));
-			}
-			k.data[offset.as_uint() as usize] = Val::Ptr(v);
-			k.length = k.data.len() as u64;
-		    }
-		} else {
-		    self.heap.insert(block.as_uint() as u64, MemBlock::new());
-		    
-		    if let Some(k) = self.heap.get_mut(&(block.as_uint() as u64)) {
-			while k.data.len() <= offset.as_uint() as usize {
-			    k.data.push(Val::Int(0));
-			}
-			k.data[offset.as_uint() as usize] = Val::Ptr(v);
-			k.length = k.data.len() as u64;
-		    }
+	    Opcode::Yield => {
+		self.yielded = true;
+	    },
+	    Opcode::JmpI => {
+		let t = ins.uint();
+		self.jump(t as i64)?;
+	    },
+	    Opcode::JeqI | Opcode::JneI | Opcode::JgtI | Opcode::JltI | Opcode::JgqI | Opcode::JlqI |
+	    Opcode::JgtuI | Opcode::JltuI | Opcode::JgquI | Opcode::JlquI | Opcode::JoI | Opcode::JnoI => {
+		let t = ins.uint();
+		if self.flags.condition(opcode) {
+		    self.jump(t as i64)?;
 		}
 	    },
-	    Opcode::Loadptr => {
-		let register = self.next_8_bits() as usize; // We cast to usize so we can use it as an index into the array
-		let number = self.get_uint();
-		self.registers[register] = Val::Ptr(number as u64); // Our registers are i32s, so we need to cast it. We'll cover that later.
+	    Opcode::Mov => {
+		let target = ins.regs[0] as usize;
+		self.registers[target] = self.registers[ins.regs[1] as usize];
 	    },
-	    Opcode::Deref => {
-		let b_addr = self.next_8_bits();
-		let block = self.registers[b_addr as usize];
-		let offset = self.registers[self.next_8_bits() as usize];
-		let target = self.next_8_bits() as usize;
-		self.registers[b_addr as usize] = Val::Ptr(block.as_uint() as u64);
-		if let Some(v) = self.heap.get(&(block.as_uint())) {
-		    self.registers[target] = v.data[offset.as_uint() as usize];
+	    Opcode::AddI => {
+		let target = ins.regs[0] as usize;
+		let r1 = self.registers[ins.regs[1] as usize];
+		let imm = Val::Int(ins.int());
+		self.registers[target] = Val::Int(self.add(r1, imm)?);
+	    },
+	    Opcode::SubI => {
+		let target = ins.regs[0] as usize;
+		let r1 = self.registers[ins.regs[1] as usize];
+		let imm = Val::Int(ins.int());
+		self.registers[target] = Val::Int(self.sub(r1, imm)?);
+	    },
+	    Opcode::MulI => {
+		let target = ins.regs[0] as usize;
+		let r1 = self.registers[ins.regs[1] as usize];
+		let imm = Val::Int(ins.int());
+		self.registers[target] = Val::Int(self.mul(r1, imm)?);
+	    },
+	    Opcode::Ret => {
+		match self.call_stack.pop() {
+		    Some(frame) => self.pc = frame.return_address,
+		    None => return Err(VmError::CallStackUnderflow { pc: self.ins_start }),
 		}
 	    },
-	    _ => {
-		return true;
+	    Opcode::Igl => {
+		return Err(VmError::IllegalOpcode { pc: self.ins_start, opcode: self.program[self.ins_start] });
 	    }
 	}
-	false
+	Ok(false)
+    }
+
+    fn add(&mut self, a: Val, b: Val) -> Result<i64, VmError> {
+	let carry = a.as_uint().overflowing_add(b.as_uint()).1;
+	self.arith_result(a.as_int().overflowing_add(b.as_int()), carry, a.as_int().saturating_add(b.as_int()))
+    }
+
+    fn sub(&mut self, a: Val, b: Val) -> Result<i64, VmError> {
+	let carry = a.as_uint() < b.as_uint();
+	self.arith_result(a.as_int().overflowing_sub(b.as_int()), carry, a.as_int().saturating_sub(b.as_int()))
+    }
+
+    fn mul(&mut self, a: Val, b: Val) -> Result<i64, VmError> {
+	let product = a.as_int().overflowing_mul(b.as_int());
+	self.arith_result(product, product.1, a.as_int().saturating_mul(b.as_int()))
+    }
+
+    /// Pushes a frame returning to the current `pc` and jumps to `target`
+    fn call(&mut self, target: i64) -> Result<(), VmError> {
+	if self.call_stack.len() >= self.max_call_depth {
+	    return Err(VmError::CallStackOverflow { pc: self.ins_start, depth: self.call_stack.len() });
+	}
+	let return_address = self.pc;
+	self.jump(target)?;
+	self.call_stack.push(Frame {
+	    return_address,
+	    target: self.pc,
+	});
+	Ok(())
     }
 
-    fn decode_opcode(&mut self) -> Opcode {
-        let opcode = Opcode::from(self.program[self.pc]);
-        self.pc += 1;
-        opcode
+    /// Picks the result of an integer operation according to `arith_mode`,
+    /// given its wrapped result, whether it overflowed, whether it carried and
+    /// its saturated result, and sets the flags to match
+    fn arith_result(&mut self, (wrapped, overflowed): (i64, bool), carry: bool, saturated: i64) -> Result<i64, VmError> {
+	let v = match self.arith_mode {
+	    _ if !overflowed => wrapped,
+	    ArithMode::Wrapping => wrapped,
+	    ArithMode::Checked => return Err(VmError::ArithmeticOverflow { pc: self.ins_start }),
+	    ArithMode::Saturating => saturated,
+	};
+	self.flags = Flags {
+	    zero: v == 0,
+	    sign: v < 0,
+	    carry,
+	    overflow: overflowed,
+	    unordered: false,
+	};
+	Ok(v)
+    }
+
+    fn read_cell(&self, block: u64, offset: u64) -> Result<Val, VmError> {
+	let k = match self.heap.get(block) {
+	    Ok(k) => k,
+	    Err(e) => return Err(self.heap_error(e, block)),
+	};
+	match k.data.get(offset as usize) {
+	    Some(cell) => Ok(*cell),
+	    None => Err(VmError::HeapOutOfBounds { pc: self.ins_start, block, offset }),
+	}
+    }
+
+    fn write_cell(&mut self, block: u64, offset: u64, v: Val) -> Result<(), VmError> {
+	let pc = self.ins_start;
+	let k = match self.heap.get_mut(block) {
+	    Ok(k) => k,
+	    Err(e) => return Err(self.heap_error(e, block)),
+	};
+	match k.data.get_mut(offset as usize) {
+	    Some(cell) => *cell = v,
+	    None => return Err(VmError::HeapOutOfBounds { pc, block, offset }),
+	}
+	if self.tracer.is_some() {
+	    self.heap_writes.push(HeapWrite { block, offset, value: v });
+	}
+	Ok(())
+    }
+
+    fn heap_error(&self, e: HeapError, block: u64) -> VmError {
+	let pc = self.ins_start;
+	match e {
+	    HeapError::UnknownBlock => VmError::UnknownBlock { pc, block },
+	    HeapError::UseAfterFree => VmError::UseAfterFree { pc, block },
+	    HeapError::DoubleFree => VmError::DoubleFree { pc, block },
+	}
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::assembler::Assembler;
+    use crate::syscall::{self, FileAccess, SharedBuffer};
 
     #[test]
     fn test_create_vm() {
@@ -291,7 +979,7 @@ mod tests {
 	let mut test_vm = VM::new();
 	let test_bytes = vec![0];
 	test_vm.program = test_bytes;
-	test_vm.run();
+	test_vm.run().unwrap();
 	assert_eq!(test_vm.pc, 1);
     }
 
@@ -300,51 +988,79 @@ mod tests {
 	let mut test_vm = VM::new();
 	let test_bytes = vec![200,0,0,0];
 	test_vm.program = test_bytes;
-	test_vm.run();
-	assert_eq!(test_vm.pc, 1);
+	assert_eq!(test_vm.run(), Err(VmError::IllegalOpcode { pc: 0, opcode: 200 }));
+	assert_eq!(test_vm.pc, 0);
     }
     #[test]
     fn test_load_opcode() {
 	let mut test_vm = VM::new();
-	test_vm.program = vec![1, 0, 0b10000000, 0, 0, 0, 0, 0, 1, 244]; // Remember, this is how we represent 500 using two u8s in little endian format
-	test_vm.run();
+	test_vm.program = vec![1, 0, 231, 7]; // -500 zigzags to 999, which is 0x67 and 7 in seven bit groups
+	test_vm.run().unwrap();
 	assert_eq!(test_vm.registers[0], Val::Int(-500));
     }
 
     #[test]
     fn test_add_opcode() {
 	let mut test_vm = VM::new();
-	test_vm.program = vec![1, 0, 0, 0, 0, 0, 0, 0, 1, 244, 1, 1, 0, 0, 0, 0, 0, 0, 0, 2, 2, 0, 1, 2]; // Remember, this is how we represent 500 using two u8s in little endian format
-	test_vm.run();
+	test_vm.program = vec![1, 0, 232, 7, 1, 1, 4, 2, 0, 1, 2]; // load r0 500; load r1 2
+	test_vm.run().unwrap();
 	assert_eq!(test_vm.registers[2], Val::Int(502));
     }
     #[test]
     fn test_sub_opcode() {
 	let mut test_vm = VM::new();
-	test_vm.program = vec![1, 0, 0, 0, 0, 0, 0, 0, 1, 244, 1, 1, 0, 0, 0, 0, 0, 0, 0, 2, 3, 0, 1, 2]; // Remember, this is how we represent 500 using two u8s in little endian format
-	test_vm.run();
+	test_vm.program = vec![1, 0, 232, 7, 1, 1, 4, 3, 0, 1, 2]; // load r0 500; load r1 2
+	test_vm.run().unwrap();
 	assert_eq!(test_vm.registers[2], Val::Int(498));
     }
     #[test]
     fn test_mul_opcode() {
 	let mut test_vm = VM::new();
-	test_vm.program = vec![1, 0, 0, 0, 0, 0, 0, 0, 1, 244, 1, 1, 0, 0, 0, 0, 0, 0, 0, 2, 4, 0, 1, 2]; // Remember, this is how we represent 500 using two u8s in little endian format
-	test_vm.run();
+	test_vm.program = vec![1, 0, 232, 7, 1, 1, 4, 4, 0, 1, 2]; // load r0 500; load r1 2
+	test_vm.run().unwrap();
 	assert_eq!(test_vm.registers[2], Val::Int(1000));
     }
     #[test]
     fn test_div_opcode() {
 	let mut test_vm = VM::new();
-	test_vm.program = vec![1, 0, 0, 0, 0, 0, 0, 0, 1, 244, 1, 1, 0, 0, 0, 0, 0, 0, 0, 2, 5, 0, 1, 2]; // Remember, this is how we represent 500 using two u8s in little endian format
-	test_vm.run();
+	test_vm.program = vec![1, 0, 232, 7, 1, 1, 4, 5, 0, 1, 2]; // load r0 500; load r1 2
+	test_vm.run().unwrap();
 	assert_eq!(test_vm.registers[2], Val::Int(250));
     }
     #[test]
+    fn test_div_remainder() {
+	let mut test_vm = VM::new();
+	for &(a, b, q, r) in &[(7, 2, 3, 1), (-7, 2, -3, -1), (7, -2, -3, 1), (-7, -2, 3, -1), (i64::MIN, -1, i64::MIN, 0)] {
+	    test_vm.registers[0] = Val::Int(a);
+	    test_vm.registers[1] = Val::Int(b);
+	    test_vm.program = vec![5, 0, 1, 2, 39, 3];
+	    test_vm.pc = 0;
+	    test_vm.run().unwrap();
+	    assert_eq!(test_vm.registers[2], Val::Int(q));
+	    assert_eq!(test_vm.registers[3], Val::Int(r));
+	}
+    }
+    #[test]
+    fn test_mod_opcode() {
+	let mut test_vm = VM::new();
+	for &(a, b, m) in &[(7, 3, 1), (-7, 3, 2), (7, -3, -2), (-7, -3, -1), (6, -3, 0), (i64::MIN, -1, 0)] {
+	    test_vm.registers[0] = Val::Int(a);
+	    test_vm.registers[1] = Val::Int(b);
+	    test_vm.program = vec![38, 0, 1, 2];
+	    test_vm.pc = 0;
+	    test_vm.run().unwrap();
+	    assert_eq!(test_vm.registers[2], Val::Int(m));
+	}
+	test_vm.registers[1] = Val::Int(0);
+	test_vm.pc = 0;
+	assert_eq!(test_vm.run(), Err(VmError::DivisionByZero { pc: 0 }));
+    }
+    #[test]
     fn test_jmp_opcode() {
 	let mut test_vm = VM::new();
 	test_vm.registers[0] = Val::Int(1);
 	test_vm.program = vec![6, 0];
-	test_vm.execute_instruction();
+	test_vm.execute_instruction().unwrap();
 	assert_eq!(test_vm.pc, 1);
     }
     
@@ -352,8 +1068,8 @@ mod tests {
     fn test_jmpf_opcode() {
 	let mut test_vm = VM::new();
 	test_vm.registers[0] = Val::Int(1);
-	test_vm.program = vec![7, 0];
-	test_vm.execute_instruction();
+	test_vm.program = vec![7, 0, 0, 0];
+	test_vm.execute_instruction().unwrap();
 	assert_eq!(test_vm.pc, 3);
     }
     #[test]
@@ -361,7 +1077,7 @@ mod tests {
 	let mut test_vm = VM::new();
 	test_vm.registers[0] = Val::Int(1);
 	test_vm.program = vec![8, 0];
-	test_vm.execute_instruction();
+	test_vm.execute_instruction().unwrap();
 	assert_eq!(test_vm.pc, 1);
     }
     #[test]
@@ -370,25 +1086,29 @@ mod tests {
 	test_vm.registers[0] = Val::Int(1);
 	test_vm.registers[1] = Val::Int(3);
 	test_vm.program = vec![9, 0, 1];
-	test_vm.execute_instruction();
-	assert_eq!(test_vm.equal_flag, CmpRes::Lt);
+	test_vm.execute_instruction().unwrap();
+	assert!(test_vm.flags.condition(Opcode::Jlt));
+	assert!(test_vm.flags.condition(Opcode::Jne));
+	assert!(!test_vm.flags.condition(Opcode::Jgq));
     }
     #[test]
     fn test_jeq_opcode() {
 	let mut test_vm = VM::new();
 	test_vm.registers[0] = Val::Int(7);
-	test_vm.equal_flag = CmpRes::Eq;
-	test_vm.program = vec![10, 0];
-	test_vm.execute_instruction();
+	test_vm.flags.zero = true;
+	test_vm.program = vec![10, 0, 0, 0, 0, 0, 0, 0];
+	test_vm.execute_instruction().unwrap();
 	assert_eq!(test_vm.pc, 7);
     }
     #[test]
     fn test_write_opcode() {
 	let mut test_vm = VM::new();
-	test_vm.registers[1] = Val::Int(1);
-	test_vm.program = vec![16, 1, 1, 0, 0, 0, 0, 0, 0, 0, 2];
-	test_vm.execute_instruction();
-	if let Some(x) = test_vm.heap.get(&1) {
+	test_vm.heap.alloc(0);
+	test_vm.registers[1] = Val::Ptr(test_vm.heap.alloc(2));
+	test_vm.registers[2] = Val::Int(1);
+	test_vm.program = vec![16, 1, 2, 4];
+	test_vm.execute_instruction().unwrap();
+	if let Ok(x) = test_vm.heap.get(1) {
 	    assert_eq!(x.data[1], Val::Int(2));
 	} else {
 	    panic!("missing block");
@@ -397,10 +1117,12 @@ mod tests {
     #[test]
     fn test_writeptr_opcode() {
 	let mut test_vm = VM::new();
-	test_vm.registers[1] = Val::Int(1);
-	test_vm.program = vec![17, 1, 1, 0, 0, 0, 0, 0, 0, 0, 2];
-	test_vm.execute_instruction();
-	if let Some(x) = test_vm.heap.get(&1) {
+	test_vm.heap.alloc(0);
+	test_vm.registers[1] = Val::Ptr(test_vm.heap.alloc(2));
+	test_vm.registers[2] = Val::Int(1);
+	test_vm.program = vec![17, 1, 2, 2];
+	test_vm.execute_instruction().unwrap();
+	if let Ok(x) = test_vm.heap.get(1) {
 	    assert_eq!(x.data[1], Val::Ptr(2));
 	} else {
 	    panic!("missing block");
@@ -409,8 +1131,577 @@ mod tests {
     #[test]
     fn test_loadptr_opcode() {
 	let mut test_vm = VM::new();
-	test_vm.program = vec![18, 0, 0, 0, 0, 0, 0, 0, 1, 244]; // Remember, this is how we represent 500 using two u8s in little endian format
-	test_vm.run();
+	test_vm.program = vec![18, 0, 244, 3]; // Unsigned immediates are not zigzagged, so 500 is 0x74 | 0x80 and 3
+	test_vm.run().unwrap();
 	assert_eq!(test_vm.registers[0], Val::Ptr(500));
     }
+    #[test]
+    fn test_call_opcode() {
+	let mut test_vm = VM::new();
+	test_vm.registers[0] = Val::Int(7);
+	test_vm.program = vec![20, 0, 0, 0, 0, 0, 0, 0];
+	test_vm.execute_instruction().unwrap();
+	assert_eq!(test_vm.pc, 7);
+	assert_eq!(test_vm.call_stack, vec![Frame { return_address: 2, target: 7 }]);
+    }
+    #[test]
+    fn test_ret_opcode() {
+	let mut test_vm = VM::new();
+	test_vm.registers[0] = Val::Int(3);
+	test_vm.program = vec![20, 0, 0, 21];
+	test_vm.run().unwrap();
+	assert_eq!(test_vm.pc, 3);
+	assert!(test_vm.call_stack.is_empty());
+    }
+    #[test]
+    fn test_call_depth_limit() {
+	let mut test_vm = VM::new();
+	test_vm.max_call_depth = 4;
+	// r0 holds 0, so `call r0` keeps calling itself
+	test_vm.program = vec![20, 0];
+	assert_eq!(test_vm.run(), Err(VmError::CallStackOverflow { pc: 0, depth: 4 }));
+	assert_eq!(test_vm.call_stack.len(), 4);
+    }
+    #[test]
+    fn test_ret_empty_call_stack() {
+	let mut test_vm = VM::new();
+	test_vm.program = vec![21];
+	assert_eq!(test_vm.run(), Err(VmError::CallStackUnderflow { pc: 0 }));
+    }
+    #[test]
+    fn test_div_by_zero() {
+	let mut test_vm = VM::new();
+	test_vm.registers[0] = Val::Int(10);
+	test_vm.program = vec![1, 1, 0, 5, 0, 1, 2];
+	assert_eq!(test_vm.run(), Err(VmError::DivisionByZero { pc: 3 }));
+	assert_eq!(test_vm.pc, 3);
+    }
+    #[test]
+    fn test_truncated_operand() {
+	let mut test_vm = VM::new();
+	test_vm.program = vec![1, 0, 0x80];
+	assert_eq!(test_vm.run(), Err(VmError::TruncatedOperand { pc: 0 }));
+	test_vm.program = vec![1, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0];
+	assert_eq!(test_vm.run(), Err(VmError::MalformedOperand { pc: 0 }));
+	test_vm.program = vec![2, 0];
+	assert_eq!(test_vm.run(), Err(VmError::TruncatedOperand { pc: 0 }));
+    }
+    #[test]
+    fn test_bad_jump_target() {
+	let mut test_vm = VM::new();
+	test_vm.registers[0] = Val::Int(3);
+	test_vm.program = vec![8, 0];
+	assert_eq!(test_vm.run(), Err(VmError::BadJumpTarget { pc: 0, target: -1 }));
+	test_vm.program = vec![6, 0];
+	assert_eq!(test_vm.run(), Err(VmError::BadJumpTarget { pc: 0, target: 3 }));
+    }
+    #[test]
+    fn test_deref_errors() {
+	let mut test_vm = VM::new();
+	test_vm.registers[1] = Val::Int(1);
+	test_vm.program = vec![19, 1, 0, 2];
+	assert_eq!(test_vm.run(), Err(VmError::UnknownBlock { pc: 0, block: 1 }));
+	test_vm.heap.alloc(3);
+	test_vm.heap.alloc(0);
+	assert_eq!(test_vm.run(), Err(VmError::HeapOutOfBounds { pc: 0, block: 1, offset: 0 }));
+    }
+    #[test]
+    fn test_load_bytecode() {
+	let mut test_vm = VM::new();
+	assert_eq!(test_vm.load_bytecode(&[1, 0, 0, 0]), Err(LoadError::BadMagic));
+	let binary = Binary {
+	    entry: 3,
+	    code: vec![1, 0, 18, 1, 1, 10],
+	    data: vec![vec![Val::Int(3)]],
+	    symbols: SymbolTable::new(),
+	};
+	test_vm.load_bytecode(&binary.to_bytes()).unwrap();
+	test_vm.run().unwrap();
+	assert_eq!(test_vm.registers[0], Val::Int(0));
+	assert_eq!(test_vm.registers[1], Val::Int(5));
+	assert_eq!(test_vm.heap.get(0).unwrap().data, vec![Val::Int(3)]);
+    }
+    #[test]
+    fn test_load_bytecode_twice() {
+	let binary = Assembler::new().assemble_binary("load r0 7\nload r1 2\ndiv r0 r1 r2\ncmp r1 r0\nhlt\n").unwrap();
+	let mut test_vm = VM::new();
+	test_vm.load_bytecode(&binary).unwrap();
+	test_vm.run().unwrap();
+	assert_eq!(test_vm.remainder, 1);
+	assert!(test_vm.flags.condition(Opcode::Jlt));
+	test_vm.load_bytecode(&binary).unwrap();
+	assert!(test_vm.registers.iter().all(|r| *r == Val::Int(0)));
+	assert_eq!(test_vm.flags, Flags::default());
+	assert_eq!(test_vm.remainder, 0);
+    }
+    #[test]
+    fn test_load_bytecode_closes_files() {
+	let dir = std::env::temp_dir().join(format!("bedrock-vm-files-{}", std::process::id()));
+	std::fs::create_dir_all(&dir).unwrap();
+	let mut asm = Assembler::new();
+	let binary = asm.assemble_binary(".data\npath: .asciiz 'out.txt'\n.code\nloadptr r0 @path\nload r1 1\nsyscall 5\nhlt\n").unwrap();
+	let mut test_vm = VM::new();
+	test_vm.io = Io::disabled();
+	test_vm.io.files = FileAccess::Within(dir.clone());
+	test_vm.load_bytecode(&binary).unwrap();
+	test_vm.run().unwrap();
+	let handle = test_vm.registers[0];
+	test_vm.load_bytecode(&binary).unwrap();
+	let mut registers = [handle];
+	assert!(test_vm.io.syscall(syscall::CLOSE, &mut registers, &mut test_vm.heap).is_err());
+	std::fs::remove_dir_all(dir).unwrap();
+    }
+    #[test]
+    fn test_alloc_opcode() {
+	let mut test_vm = VM::new();
+	test_vm.registers[1] = Val::Int(3);
+	test_vm.program = vec![22, 0, 1, 22, 2, 1];
+	test_vm.run().unwrap();
+	assert_eq!(test_vm.registers[0], Val::Ptr(0));
+	assert_eq!(test_vm.registers[2], Val::Ptr(1));
+	assert_eq!(test_vm.heap.get(1).unwrap().data.len(), 3);
+	test_vm.registers[1] = Val::Int(-1);
+	test_vm.program = vec![22, 0, 1];
+	test_vm.pc = 0;
+	assert_eq!(test_vm.run(), Err(VmError::BadAllocSize { pc: 0, size: -1 }));
+	// Over the default block size limit
+	test_vm.registers[1] = Val::Int(1 << 62);
+	test_vm.pc = 0;
+	let error = AllocError::BlockTooLarge { max: DEFAULT_MAX_BLOCK_SIZE };
+	assert_eq!(test_vm.run(), Err(VmError::AllocLimit { pc: 0, size: 1 << 62, error }));
+	// Too big to ever allocate, which must fail rather than abort
+	test_vm.heap.max_block_size = usize::MAX;
+	test_vm.pc = 0;
+	assert_eq!(test_vm.run(), Err(VmError::BadAllocSize { pc: 0, size: 1 << 62 }));
+    }
+    #[test]
+    fn test_free_opcode() {
+	let mut test_vm = VM::new();
+	test_vm.registers[0] = Val::Ptr(test_vm.heap.alloc(1));
+	test_vm.program = vec![23, 0];
+	test_vm.run().unwrap();
+	assert!(test_vm.heap.is_empty());
+	test_vm.pc = 0;
+	assert_eq!(test_vm.run(), Err(VmError::DoubleFree { pc: 0, block: 0 }));
+	test_vm.registers[0] = Val::Ptr(5);
+	assert_eq!(test_vm.run(), Err(VmError::UnknownBlock { pc: 0, block: 5 }));
+    }
+    #[test]
+    fn test_use_after_free() {
+	let mut test_vm = VM::new();
+	test_vm.registers[0] = Val::Ptr(test_vm.heap.alloc(1));
+	test_vm.program = vec![23, 0, 19, 0, 1, 2];
+	assert_eq!(test_vm.run(), Err(VmError::UseAfterFree { pc: 2, block: 0 }));
+	test_vm.program = vec![16, 0, 1, 4];
+	test_vm.pc = 0;
+	assert_eq!(test_vm.run(), Err(VmError::UseAfterFree { pc: 0, block: 0 }));
+    }
+    #[test]
+    fn test_write_out_of_bounds() {
+	let mut test_vm = VM::new();
+	test_vm.registers[0] = Val::Ptr(test_vm.heap.alloc(1));
+	test_vm.registers[1] = Val::Int(1);
+	test_vm.program = vec![16, 0, 1, 4];
+	assert_eq!(test_vm.run(), Err(VmError::HeapOutOfBounds { pc: 0, block: 0, offset: 1 }));
+    }
+    #[test]
+    fn test_alloc_collects_garbage() {
+	let mut test_vm = VM::new();
+	test_vm.heap.gc_threshold = 4;
+	test_vm.load_data(&[vec![Val::Int(7)]]);
+	test_vm.registers[1] = Val::Int(1);
+	// r0 is overwritten on each alloc, leaving the previous block unreachable
+	test_vm.program = vec![22, 0, 1, 22, 0, 1, 22, 0, 1, 22, 0, 1, 22, 0, 1];
+	test_vm.run().unwrap();
+	let stats = test_vm.heap.stats();
+	assert_eq!(stats.collections, 1);
+	assert_eq!(stats.freed, 2);
+	assert_eq!(stats.live, 4);
+	assert!(test_vm.heap.get(0).is_ok());
+	assert_eq!(test_vm.registers[0], Val::Ptr(5));
+    }
+    #[test]
+    fn test_float_arithmetic() {
+	let mut test_vm = VM::new();
+	let mut program = vec![24, 0];
+	program.extend_from_slice(&2.5f64.to_bits().to_be_bytes());
+	program.extend_from_slice(&[24, 1]);
+	program.extend_from_slice(&(-0.5f64).to_bits().to_be_bytes());
+	program.extend_from_slice(&[25, 0, 1, 2, 26, 0, 1, 3, 27, 0, 1, 4, 28, 0, 1, 5]);
+	test_vm.program = program;
+	test_vm.run().unwrap();
+	assert_eq!(test_vm.registers[0], Val::Float(2.5));
+	assert_eq!(test_vm.registers[2], Val::Float(2.0));
+	assert_eq!(test_vm.registers[3], Val::Float(3.0));
+	assert_eq!(test_vm.registers[4], Val::Float(-1.25));
+	assert_eq!(test_vm.registers[5], Val::Float(-5.0));
+    }
+    #[test]
+    fn test_float_conversions() {
+	let mut test_vm = VM::new();
+	test_vm.registers[0] = Val::Int(-3);
+	test_vm.registers[1] = Val::Float(7.9);
+	test_vm.registers[2] = Val::Float(f64::NAN);
+	test_vm.program = vec![29, 3, 0, 30, 4, 1, 30, 5, 2];
+	test_vm.run().unwrap();
+	assert_eq!(test_vm.registers[3], Val::Float(-3.0));
+	assert_eq!(test_vm.registers[4], Val::Int(7));
+	assert_eq!(test_vm.registers[5], Val::Int(0));
+    }
+    #[test]
+    fn test_cmp_floats() {
+	let mut test_vm = VM::new();
+	test_vm.registers[0] = Val::Float(1.5);
+	test_vm.registers[1] = Val::Int(1);
+	test_vm.registers[2] = Val::Float(f64::NAN);
+	test_vm.program = vec![9, 0, 1];
+	test_vm.run().unwrap();
+	assert!(test_vm.flags.condition(Opcode::Jgt));
+	assert!(!test_vm.flags.condition(Opcode::Jlq));
+	test_vm.program = vec![9, 2, 2];
+	test_vm.pc = 0;
+	test_vm.run().unwrap();
+	for op in &[Opcode::Jeq, Opcode::Jgt, Opcode::Jlt, Opcode::Jgq, Opcode::Jlq, Opcode::Jgtu, Opcode::Jltu, Opcode::Jgqu, Opcode::Jlqu] {
+	    assert!(!test_vm.flags.condition(*op));
+	}
+	assert!(test_vm.flags.condition(Opcode::Jne));
+    }
+    #[test]
+    fn test_bitwise_opcodes() {
+	let mut test_vm = VM::new();
+	test_vm.registers[0] = Val::Int(0b1100);
+	test_vm.registers[1] = Val::Int(0b1010);
+	test_vm.program = vec![31, 0, 1, 2, 32, 0, 1, 3, 33, 0, 1, 4, 34, 5, 0];
+	test_vm.run().unwrap();
+	assert_eq!(test_vm.registers[2], Val::Int(0b1000));
+	assert_eq!(test_vm.registers[3], Val::Int(0b1110));
+	assert_eq!(test_vm.registers[4], Val::Int(0b0110));
+	assert_eq!(test_vm.registers[5], Val::Int(!0b1100));
+    }
+    #[test]
+    fn test_shift_opcodes() {
+	let mut test_vm = VM::new();
+	test_vm.registers[0] = Val::Int(-16);
+	test_vm.registers[1] = Val::Int(2);
+	test_vm.program = vec![35, 0, 1, 2, 36, 0, 1, 3, 37, 0, 1, 4];
+	test_vm.run().unwrap();
+	assert_eq!(test_vm.registers[2], Val::Int(-64));
+	assert_eq!(test_vm.registers[3], Val::Int(((-16i64 as u64) >> 2) as i64));
+	assert_eq!(test_vm.registers[4], Val::Int(-4));
+    }
+    #[test]
+    fn test_shift_by_64_or_more() {
+	let mut test_vm = VM::new();
+	test_vm.registers[0] = Val::Int(-16);
+	test_vm.registers[6] = Val::Int(16);
+	for amount in &[64, 65, 1 << 40, -1] {
+	    test_vm.registers[1] = Val::Int(*amount);
+	    test_vm.program = vec![35, 0, 1, 2, 36, 0, 1, 3, 37, 0, 1, 4, 37, 6, 1, 5];
+	    test_vm.pc = 0;
+	    test_vm.run().unwrap();
+	    assert_eq!(test_vm.registers[2], Val::Int(0));
+	    assert_eq!(test_vm.registers[3], Val::Int(0));
+	    assert_eq!(test_vm.registers[4], Val::Int(-1));
+	    assert_eq!(test_vm.registers[5], Val::Int(0));
+	}
+	test_vm.registers[1] = Val::Int(63);
+	test_vm.program = vec![35, 6, 1, 2, 36, 0, 1, 3];
+	test_vm.pc = 0;
+	test_vm.run().unwrap();
+	assert_eq!(test_vm.registers[2], Val::Int(0));
+	assert_eq!(test_vm.registers[3], Val::Int(1));
+    }
+    #[test]
+    fn test_arith_modes() {
+	// add, sub, mul and div that each overflow
+	let program = vec![2, 0, 1, 2, 3, 3, 1, 4, 4, 0, 1, 5, 5, 3, 6, 7];
+	let mut test_vm = VM::new();
+	test_vm.registers[0] = Val::Int(i64::MAX);
+	test_vm.registers[1] = Val::Int(2);
+	test_vm.registers[3] = Val::Int(i64::MIN);
+	test_vm.registers[6] = Val::Int(-1);
+	test_vm.program = program.clone();
+	test_vm.run().unwrap();
+	assert_eq!(test_vm.registers[2], Val::Int(i64::MIN + 1));
+	assert_eq!(test_vm.registers[4], Val::Int(i64::MAX - 1));
+	assert_eq!(test_vm.registers[5], Val::Int(-2));
+	assert_eq!(test_vm.registers[7], Val::Int(i64::MIN));
+
+	test_vm.arith_mode = ArithMode::Saturating;
+	test_vm.pc = 0;
+	test_vm.run().unwrap();
+	assert_eq!(test_vm.registers[2], Val::Int(i64::MAX));
+	assert_eq!(test_vm.registers[4], Val::Int(i64::MIN));
+	assert_eq!(test_vm.registers[5], Val::Int(i64::MAX));
+	assert_eq!(test_vm.registers[7], Val::Int(i64::MAX));
+
+	test_vm.arith_mode = ArithMode::Checked;
+	test_vm.pc = 0;
+	assert_eq!(test_vm.run(), Err(VmError::ArithmeticOverflow { pc: 0 }));
+	test_vm.registers[0] = Val::Int(1);
+	test_vm.pc = 0;
+	assert_eq!(test_vm.run(), Err(VmError::ArithmeticOverflow { pc: 4 }));
+    }
+    #[test]
+    fn test_overflow_jumps() {
+	let mut test_vm = VM::new();
+	test_vm.registers[0] = Val::Int(i64::MAX);
+	test_vm.registers[1] = Val::Int(1);
+	test_vm.registers[9] = Val::Int(8);
+	// add r0 r1 r2; jo r9; hlt; hlt
+	test_vm.program = vec![2, 0, 1, 2, 40, 9, 0, 0, 0];
+	test_vm.run().unwrap();
+	assert_eq!(test_vm.pc, 9);
+	test_vm.registers[0] = Val::Int(1);
+	test_vm.pc = 0;
+	test_vm.run().unwrap();
+	assert_eq!(test_vm.pc, 7);
+	// jno takes the jump when nothing overflowed
+	test_vm.program[4] = 41;
+	test_vm.pc = 0;
+	test_vm.run().unwrap();
+	assert_eq!(test_vm.pc, 9);
+    }
+    #[test]
+    fn test_jne_opcode() {
+	let mut test_vm = VM::new();
+	test_vm.registers[0] = Val::Int(5);
+	test_vm.registers[1] = Val::Int(6);
+	test_vm.registers[2] = Val::Int(7);
+	// cmp r0 r1; jne r2; hlt
+	test_vm.program = vec![9, 0, 1, 11, 2, 0, 0];
+	test_vm.run().unwrap();
+	assert_eq!(test_vm.pc, 7);
+	test_vm.registers[1] = Val::Int(5);
+	test_vm.pc = 0;
+	test_vm.run().unwrap();
+	assert_eq!(test_vm.pc, 6);
+    }
+    #[test]
+    fn test_signed_and_unsigned_compares() {
+	let mut test_vm = VM::new();
+	test_vm.registers[0] = Val::Ptr(u64::MAX);
+	test_vm.registers[1] = Val::Ptr(1);
+	test_vm.program = vec![42, 0, 1];
+	test_vm.run().unwrap();
+	assert!(test_vm.flags.condition(Opcode::Jgtu));
+	assert!(test_vm.flags.condition(Opcode::Jgqu));
+	assert!(!test_vm.flags.condition(Opcode::Jltu));
+	// The same bits are -1 < 1 when signed
+	assert!(test_vm.flags.condition(Opcode::Jlt));
+	// Signed compares stay correct when the subtraction overflows
+	test_vm.registers[0] = Val::Int(i64::MIN);
+	test_vm.registers[1] = Val::Int(1);
+	test_vm.program = vec![9, 0, 1];
+	test_vm.pc = 0;
+	test_vm.run().unwrap();
+	assert!(test_vm.flags.overflow);
+	assert!(test_vm.flags.condition(Opcode::Jlt));
+	assert!(test_vm.flags.condition(Opcode::Jgtu));
+	test_vm.registers[1] = Val::Int(i64::MIN);
+	test_vm.pc = 0;
+	test_vm.run().unwrap();
+	assert!(test_vm.flags.condition(Opcode::Jlqu));
+	assert!(test_vm.flags.condition(Opcode::Jgq));
+	assert!(!test_vm.flags.condition(Opcode::Jgt));
+    }
+    #[test]
+    fn test_arithmetic_sets_flags() {
+	let mut test_vm = VM::new();
+	test_vm.registers[0] = Val::Int(-1);
+	test_vm.registers[1] = Val::Int(1);
+	test_vm.program = vec![2, 0, 1, 2];
+	test_vm.run().unwrap();
+	assert_eq!(test_vm.flags, Flags { zero: true, sign: false, carry: true, overflow: false, unordered: false });
+	test_vm.program = vec![3, 2, 1, 2];
+	test_vm.pc = 0;
+	test_vm.run().unwrap();
+	assert_eq!(test_vm.flags, Flags { zero: false, sign: true, carry: true, overflow: false, unordered: false });
+    }
+    #[test]
+    fn test_mov_opcode() {
+	let mut test_vm = VM::new();
+	test_vm.registers[1] = Val::Ptr(9);
+	test_vm.program = vec![47, 0, 1];
+	test_vm.run().unwrap();
+	assert_eq!(test_vm.registers[0], Val::Ptr(9));
+    }
+    #[test]
+    fn test_immediate_arithmetic() {
+	let mut test_vm = VM::new();
+	test_vm.registers[1] = Val::Int(10);
+	test_vm.program = vec![48, 0, 1, 10, 49, 2, 1, 9, 50, 3, 1, 6];
+	test_vm.run().unwrap();
+	assert_eq!(test_vm.registers[0], Val::Int(15));
+	assert_eq!(test_vm.registers[2], Val::Int(15));
+	assert_eq!(test_vm.registers[3], Val::Int(30));
+	test_vm.registers[1] = Val::Int(i64::MAX);
+	test_vm.arith_mode = ArithMode::Checked;
+	test_vm.pc = 0;
+	assert_eq!(test_vm.run(), Err(VmError::ArithmeticOverflow { pc: 0 }));
+    }
+    #[test]
+    fn test_immediate_jumps() {
+	let mut test_vm = VM::new();
+	// jmpi 3; hlt; jeqi 7 (not taken); hlt; hlt; hlt
+	test_vm.program = vec![51, 3, 0, 52, 7, 0, 0, 0];
+	test_vm.run().unwrap();
+	assert_eq!(test_vm.pc, 6);
+	test_vm.flags.zero = true;
+	test_vm.pc = 3;
+	test_vm.run().unwrap();
+	assert_eq!(test_vm.pc, 8);
+	// calli 3; hlt; ret
+	test_vm.program = vec![64, 3, 0, 21];
+	test_vm.pc = 0;
+	test_vm.run().unwrap();
+	assert_eq!(test_vm.pc, 3);
+	assert!(test_vm.call_stack.is_empty());
+    }
+    #[test]
+    fn test_callnative_opcode() {
+	let mut asm = Assembler::new();
+	let source = ".extern missing\n.extern sum\n.extern fail\nload r0 40\nload r1 2\nload r2 9\ncallnative @sum\nhlt\ncallnative @missing\ncallnative @fail\n";
+	let code = asm.assemble(source).unwrap();
+	let mut test_vm = VM::new();
+	test_vm.program = code;
+	test_vm.symbols = asm.symbols.clone();
+	test_vm.register_native("sum", 2, |args, _| Ok(Val::Int(args[0].as_int() + args[1].as_int())));
+	test_vm.register_native("fail", 0, |_, _| Err("no".to_string()));
+	test_vm.run().unwrap();
+	assert_eq!(test_vm.registers[0], Val::Int(42));
+	assert_eq!(test_vm.registers[2], Val::Int(9));
+	assert_eq!(test_vm.run(), Err(VmError::UnknownNative { pc: 16, index: 0 }));
+	test_vm.register_native("missing", 0, |_, heap| Ok(Val::Ptr(heap.alloc(1))));
+	test_vm.run().unwrap_err();
+	assert_eq!(test_vm.registers[0], Val::Ptr(0));
+	assert_eq!(test_vm.pc, 22);
+	assert_eq!(test_vm.run(), Err(VmError::NativeFailed { pc: 22, message: "no".to_string() }));
+    }
+    #[test]
+    fn test_syscall_opcode() {
+	let mut asm = Assembler::new();
+	let code = asm.assemble(".data\ngreeting: .asciiz 'hi '\n.code\nloadptr r0 @greeting\nsyscall 1\nload r0 42\nsyscall 0\nsyscall 99\n").unwrap();
+	let out = SharedBuffer::new();
+	let mut test_vm = VM::new();
+	test_vm.io = Io::disabled();
+	test_vm.io.stdout = Some(Box::new(out.clone()));
+	test_vm.program = code;
+	test_vm.load_data(&asm.data);
+	assert_eq!(test_vm.run(), Err(VmError::UnknownSyscall { pc: 14, number: 99 }));
+	assert_eq!(out.contents(), b"hi 42".to_vec());
+	test_vm.io.stdout = None;
+	test_vm.pc = 0;
+	let message = "stdout is disabled".to_string();
+	assert_eq!(test_vm.run(), Err(VmError::SyscallFailed { pc: 7, number: 1, message }));
+    }
+    #[test]
+    fn test_run_decoded_matches_run() {
+	let mut programs: Vec<Vec<u8>> = [
+	    "load r0 10\nload r1 0\nloop: addi r1 r1 3\nsubi r0 r0 1\nload r2 0\ncmp r0 r2\njgt @loop\ncall @f\nhlt\nf: muli r1 r1 2\nret\n",
+	    // Lands on the register byte of the load, which is a hlt
+	    "load r0 1\njmp r0\n",
+	    "load r0 7\nload r1 0\ndiv r0 r1 r2\n",
+	].iter().map(|p| Assembler::new().assemble(p).unwrap()).collect();
+	programs.push(vec![1, 0, 10, 1, 0]);
+	for program in programs {
+	    let mut slow = VM::new();
+	    slow.program = program.clone();
+	    let mut fast = VM::new();
+	    fast.program = program;
+	    assert_eq!(slow.run(), fast.run_decoded());
+	    assert_eq!(slow.pc, fast.pc);
+	    assert_eq!(slow.registers[..4], fast.registers[..4]);
+	    assert_eq!(slow.flags, fast.flags);
+	}
+    }
+
+    #[test]
+    fn test_tracer() {
+	use crate::syscall::SharedBuffer;
+	use crate::trace::{read_trace, HeapWrite, JsonTracer};
+	let program = Assembler::new().assemble("load r0 2\nalloc r1 r0\nload r2 1\nwrite r1 r2 7\nwrite r1 r0 7\n").unwrap();
+	let mut traces = vec![];
+	for decoded in &[false, true] {
+	    let out = SharedBuffer::new();
+	    let mut test_vm = VM::new();
+	    test_vm.program = program.clone();
+	    test_vm.tracer = Some(Box::new(JsonTracer::new(out.clone())));
+	    let result = if *decoded { test_vm.run_decoded() } else { test_vm.run() };
+	    assert!(result.is_err());
+	    test_vm.tracer.as_mut().unwrap().finish().unwrap();
+	    traces.push(read_trace(&out.contents()).unwrap());
+	}
+	assert_eq!(traces[0], traces[1]);
+	let events = &traces[0];
+	assert_eq!(events.len(), 5);
+	assert_eq!(events[0].writes, vec![(0, Val::Int(2))]);
+	assert_eq!(events[1].reads, vec![(1, Val::Int(0)), (0, Val::Int(2))]);
+	assert_eq!(events[3].heap_writes, vec![HeapWrite { block: 0, offset: 1, value: Val::Int(7) }]);
+	assert!(events[3].error.is_none());
+	assert_eq!(events[4].error, Some(VmError::HeapOutOfBounds { pc: events[4].pc, block: 0, offset: 2 }.to_string()));
+    }
+
+    #[test]
+    fn test_tracer_ignores_unchanged_nan() {
+	use crate::syscall::SharedBuffer;
+	use crate::trace::{diff, read_trace, JsonTracer};
+	let out = SharedBuffer::new();
+	let mut test_vm = VM::new();
+	test_vm.program = Assembler::new().assemble("load r0 1\nload r0 2\n").unwrap();
+	test_vm.registers[5] = Val::Float(f64::NAN);
+	test_vm.tracer = Some(Box::new(JsonTracer::new(out.clone())));
+	test_vm.run().unwrap();
+	test_vm.tracer.as_mut().unwrap().finish().unwrap();
+	let events = read_trace(&out.contents()).unwrap();
+	assert_eq!(events[0].writes, vec![(0, Val::Int(1))]);
+	assert_eq!(events[1].writes, vec![(0, Val::Int(2))]);
+	assert_eq!(diff(&events, &events), None);
+    }
+
+    #[test]
+    fn test_run_for() {
+	let mut test_vm = VM::new();
+	test_vm.program = Assembler::new().assemble("loop: addi r0 r0 1\njmp @loop\n").unwrap();
+	assert_eq!(test_vm.run_for(10), RunStatus::OutOfFuel);
+	assert_eq!(test_vm.registers[0], Val::Int(5));
+	assert_eq!(test_vm.run_for(3), RunStatus::OutOfFuel);
+	assert_eq!(test_vm.registers[0], Val::Int(7));
+	assert_eq!(test_vm.pc, 4);
+
+	test_vm = VM::new();
+	test_vm.program = Assembler::new().assemble("load r0 1\nyield\nload r0 2\ndiv r0 r1 r2\n").unwrap();
+	assert_eq!(test_vm.run_for(100), RunStatus::Waiting);
+	assert_eq!(test_vm.registers[0], Val::Int(1));
+	assert_eq!(test_vm.run_for(2), RunStatus::Error(VmError::DivisionByZero { pc: 7 }));
+	assert_eq!(test_vm.registers[0], Val::Int(2));
+	test_vm.program.truncate(7);
+	assert_eq!(test_vm.run_for(0), RunStatus::Halted);
+    }
+
+    #[test]
+    fn test_limits() {
+	let mut test_vm = VM::new();
+	test_vm.set_limits(Limits { max_heap_cells: 10, max_block_size: 8, max_blocks: 2, max_call_depth: 3 });
+	assert_eq!(test_vm.limits().max_call_depth, 3);
+	test_vm.registers[1] = Val::Int(1 << 40);
+	test_vm.program = Assembler::new().assemble("alloc r0 r1\n").unwrap();
+	let error = AllocError::BlockTooLarge { max: 8 };
+	assert_eq!(test_vm.run(), Err(VmError::AllocLimit { pc: 0, size: 1 << 40, error }));
+	assert!(test_vm.heap.is_empty());
+
+	// Blocks that are no longer referenced are collected to make room
+	test_vm.pc = 0;
+	test_vm.registers[1] = Val::Int(4);
+	test_vm.program = Assembler::new().assemble("alloc r2 r1\nalloc r2 r1\nalloc r2 r1\nalloc r3 r1\nalloc r4 r1\n").unwrap();
+	let error = AllocError::TooManyBlocks { max: 2 };
+	assert_eq!(test_vm.run(), Err(VmError::AllocLimit { pc: 12, size: 4, error }));
+	assert_eq!(test_vm.heap.cells(), 8);
+	assert_eq!(test_vm.heap.stats().freed, 2);
+
+	test_vm = VM::new();
+	test_vm.set_limits(Limits { max_call_depth: 3, ..Limits::default() });
+	test_vm.program = Assembler::new().assemble("f: call @f\n").unwrap();
+	assert_eq!(test_vm.run(), Err(VmError::CallStackOverflow { pc: 0, depth: 3 }));
+    }
 }